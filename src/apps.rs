@@ -0,0 +1,83 @@
+use anyhow::Result;
+use gotify::{models::Application, ClientClient as GotifyClient};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+pub struct AppInfo {
+    pub name: String,
+    pub description: String,
+    // path of the image relative to the gotify url
+    pub image: String,
+}
+
+/// Cache of the gotify applications.
+/// The applications are refetched periodically and whenever an unknown app id is looked up.
+pub struct AppRegistry<'a> {
+    client: &'a GotifyClient,
+    apps: Vec<Application>,
+    fetched: Option<Instant>,
+    refresh_interval: Duration,
+}
+
+impl<'a> AppRegistry<'a> {
+    pub fn new(client: &'a GotifyClient, refresh_interval: Duration) -> AppRegistry<'a> {
+        AppRegistry {
+            client,
+            apps: Vec::new(),
+            fetched: None,
+            refresh_interval,
+        }
+    }
+
+    pub async fn refresh(&mut self) -> Result<()> {
+        debug!("Fetching gotify applications");
+        self.apps = self.client.get_applications().await?;
+        self.fetched = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Refresh the cache, keeping the stale applications if that fails.
+    async fn try_refresh(&mut self) {
+        if let Err(e) = self.refresh().await {
+            warn!("Could not refresh gotify applications: {:?}", e);
+        }
+    }
+
+    fn find(&self, id: i64) -> Option<&Application> {
+        self.apps.iter().find(|a| a.id == id)
+    }
+
+    pub async fn get(&mut self, id: i64) -> Option<&Application> {
+        let expired = self
+            .fetched
+            .map_or(true, |f| f.elapsed() >= self.refresh_interval);
+        if expired || self.find(id).is_none() {
+            self.try_refresh().await;
+        }
+        self.find(id)
+    }
+
+    /// Name of the application, `app#<id>` if it is unknown even after a refresh.
+    pub async fn name(&mut self, id: i64) -> String {
+        self.info(id).await.name
+    }
+
+    /// Details of the application, named `app#<id>` if it is unknown even after a refresh.
+    pub async fn info(&mut self, id: i64) -> AppInfo {
+        match self.get(id).await {
+            Some(app) => AppInfo {
+                name: app.name.clone(),
+                description: app.description.clone(),
+                image: app.image.clone(),
+            },
+            None => {
+                warn!("Could not find app with id {}", id);
+                AppInfo {
+                    name: format!("app#{}", id),
+                    description: String::new(),
+                    image: String::new(),
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,12 @@
-use crate::{config, session};
+use crate::{
+    apps::AppRegistry,
+    config, date, dead_letter, markdown,
+    outbox::{self, is_unreachable, Outbox, MAX_BACKOFF, MIN_BACKOFF},
+    rules::Rules,
+    session,
+    store::{self, Delivery},
+    templates::{self, Message},
+};
 use anyhow::{Error, Result};
 use futures_util::StreamExt;
 use gotify::ClientClient as GotifyClient;
@@ -7,50 +15,32 @@ use matrix_sdk::{
     config::SyncSettings,
     ruma::{
         api::client::filter::FilterDefinition, events::room::message::RoomMessageEventContent,
-        RoomId,
+        OwnedRoomId, OwnedTransactionId, RoomId, TransactionId,
     },
-    Client as MatrixClient, Room,
+    Client as MatrixClient, Room, RoomState,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    time::{Duration, Instant},
 };
-use std::path::Path;
 use tracing::{debug, info, warn};
-
-struct Message {
-    app: String,
-    title: Option<String>,
-    message: String,
-}
-
-impl Message {
-    pub fn render(&self, handlebars: &Handlebars, template: &str) -> Result<String> {
-        let output = handlebars.render(
-            template,
-            &serde_json::json!({
-                "app": self.app,
-                "title": self.title.clone().unwrap_or_default(),
-                "message": self.message
-            }),
-        )?;
-        Ok(format!("{}", output))
-    }
-}
-
-macro_rules! register {
-    ($templates:expr, $p1:ident.$p2:ident.$p3:ident.$p4:ident) => {
-        debug!("Registering template {}_{}", stringify!($p4), stringify!($p3));
-        if let Some(template) = &$p1.$p2.$p3.$p4 {
-            $templates.register_template_string(
-                format!("{}_{}", stringify!($p4), stringify!($p3)).as_str(),
-                template,
-            )?;
-        }
-    };
-}
+use url::Url;
 
 struct Converter<'a> {
-    apps: Vec<gotify::models::Application>,
+    apps: AppRegistry<'a>,
     handlebars: Handlebars<'a>,
+    // the same templates without html escaping for the plain body
+    plain_handlebars: Handlebars<'a>,
     low: i32,
     high: i32,
+    room_id: OwnedRoomId,
+    routes: Vec<config::Route>,
+    gotify: config::Gotify,
+    rules: Rules,
+    app_config: HashMap<String, config::GotifyApp>,
+    gotify_url: Url,
+    date_format: date::DateFormat,
 }
 
 impl Converter<'_> {
@@ -58,103 +48,253 @@ impl Converter<'_> {
         client: &'a GotifyClient,
         config: &config::Config,
     ) -> Result<Converter<'a>> {
-        let apps = client.get_applications().await?;
-
-        // register all templates
-        let mut handlebars = Handlebars::new();
-        handlebars.register_template_string("plain", config.gotify.plain.clone())?;
-        handlebars.register_template_string("html", config.gotify.html.clone())?;
-        register!(handlebars, config.gotify.low.plain);
-        register!(handlebars, config.gotify.low.html);
-        register!(handlebars, config.gotify.normal.plain);
-        register!(handlebars, config.gotify.normal.html);
-        register!(handlebars, config.gotify.high.plain);
-        register!(handlebars, config.gotify.high.html);
+        let mut apps = AppRegistry::new(
+            client,
+            Duration::from_secs(config.gotify.app_refresh_interval),
+        );
+        apps.refresh().await?;
+
+        let handlebars = templates::build(config)?;
+        let plain_handlebars = templates::without_escaping(&handlebars);
         Ok(Converter {
             apps,
             handlebars,
+            plain_handlebars,
             low: config.gotify.threshold_low,
             high: config.gotify.threshold_high,
+            room_id: RoomId::parse(&config.matrix.room_id)?,
+            routes: config.route.clone(),
+            gotify: config.gotify.clone(),
+            rules: Rules::new(&config.gotify.rule)?,
+            app_config: config.gotify.app.clone(),
+            gotify_url: config.gotify.url.clone(),
+            date_format: date::DateFormat::new(&config.gotify)?,
         })
     }
 
-    pub fn render(&self, message: &Message, kind: &str, prio: &str) -> Result<String> {
-        let templates = self.handlebars.get_templates();
+    /// Find the room a message should be sent to.
+    /// App routes take precedence over priority rooms, which take precedence over the default room.
+    pub fn target_room(
+        &self,
+        app_id: i64,
+        app_name: &str,
+        prio: &config::GotifyPriority,
+    ) -> Result<OwnedRoomId> {
+        if let Some(route) = self.routes.iter().find(|r| r.matches(app_id, app_name)) {
+            return Ok(RoomId::parse(&route.room_id)?);
+        }
+        match &self.gotify.format(prio).room_id {
+            Some(room_id) => Ok(RoomId::parse(room_id)?),
+            None => Ok(self.room_id.clone()),
+        }
+    }
+
+    pub fn render(
+        &self,
+        message: &Message,
+        kind: &str,
+        prio: &str,
+        template: Option<&str>,
+    ) -> Result<String> {
+        let handlebars = if kind == "plain" {
+            &self.plain_handlebars
+        } else {
+            &self.handlebars
+        };
+        let templates = handlebars.get_templates();
+        if let Some(name) = template {
+            let template_id = format!("{}:{}", kind, name);
+            if templates.contains_key(&template_id) {
+                return message.render(handlebars, &template_id);
+            }
+        }
         let template_id = format!("{}_{}", kind, prio);
         if templates.contains_key(&template_id) {
-            message.render(&self.handlebars, &template_id)
+            message.render(handlebars, &template_id)
         } else {
-            message.render(&self.handlebars, kind)
+            message.render(handlebars, kind)
         }
     }
-    pub fn convert(&self, message: &gotify::models::Message) -> Result<RoomMessageEventContent> {
+
+    /// Convert a gotify message into the target room and content, `None` if a rule drops it.
+    pub async fn convert(
+        &mut self,
+        message: &gotify::models::Message,
+    ) -> Result<Option<(OwnedRoomId, RoomMessageEventContent)>> {
         use config::GotifyPriority;
-        let app = &self
-            .apps
-            .iter()
-            .find(|&a| a.id == message.appid)
-            .ok_or(Error::msg("Could not find app from id"))?
-            .name;
-
-        let prio = GotifyPriority::from_thresholds(message.priority.into(), self.low, self.high);
+        let app_info = self.apps.info(message.appid).await;
+        let app = &app_info.name;
+
+        let priority: i32 = message.priority.into();
+        let rule = self.rules.evaluate(
+            app,
+            priority,
+            message.title.as_deref().unwrap_or_default(),
+            &message.message,
+        );
+        if let Some((i, rule)) = rule {
+            debug!("Message with id {} matched rule #{}", message.id, i);
+            if rule.drop {
+                return Ok(None);
+            }
+        }
+        let rule = rule.map(|(_, r)| r);
+
+        let prio = rule
+            .and_then(|r| r.priority)
+            .unwrap_or_else(|| GotifyPriority::from_thresholds(priority, self.low, self.high));
+        let room_id = match rule.and_then(|r| r.room_id.as_deref()) {
+            Some(room_id) => RoomId::parse(room_id)?,
+            None => self.target_room(message.appid, app, &prio)?,
+        };
+        // the rule template takes precedence over the app and then the priority template
+        let template = rule
+            .and_then(|r| r.template.as_deref())
+            .or_else(|| self.app_config.get(app).and_then(|a| a.template.as_deref()))
+            .or_else(|| self.gotify.format(&prio).template.as_deref());
+        let is_markdown = self
+            .app_config
+            .get(app)
+            .and_then(|a| a.markdown)
+            .unwrap_or_else(|| markdown::is_markdown(message));
+        let message_html = if is_markdown {
+            markdown::to_html(&message.message)
+        } else {
+            handlebars::html_escape(&message.message)
+        };
+        let app_image = match app_info.image.as_str() {
+            "" => String::new(),
+            image => image_url(&self.gotify_url, image)?.to_string(),
+        };
+        let raw_date = raw_date(message)?;
+        let timestamp = raw_date.as_str().unwrap_or_default().to_string();
+        let date = match date::parse(&raw_date) {
+            Some(date) => self.date_format.format(&date),
+            None => timestamp.clone(),
+        };
         let message = Message {
+            id: message.id,
             app: app.to_string(),
-            title: message.title.clone(),
+            app_id: message.appid,
+            app_description: app_info.description.clone(),
+            app_image,
+            title: message.title.clone().unwrap_or_default(),
             message: message.message.clone(),
+            message_html,
+            priority,
+            priority_name: prio.name(),
+            date,
+            timestamp,
+            extras: serde_json::to_value(&message.extras)?,
         };
 
-        let plain: String;
-        let html: String;
-        match prio {
-            GotifyPriority::Low => {
-                plain = self.render(&message, "plain", "low")?;
-                html = self.render(&message, "html", "low")?;
-            }
-            GotifyPriority::Normal => {
-                plain = self.render(&message, "plain", "normal")?;
-                html = self.render(&message, "html", "normal")?;
-            }
-            GotifyPriority::High => {
-                plain = self.render(&message, "plain", "high")?;
-                html = self.render(&message, "html", "high")?;
-            }
-        }
+        let plain = self.render(&message, "plain", prio.name(), template)?;
+        let html = self.render(&message, "html", prio.name(), template)?;
+
+        Ok(Some((
+            room_id,
+            RoomMessageEventContent::text_html(plain, html),
+        )))
+    }
+}
 
-        Ok(RoomMessageEventContent::text_html(plain, html))
+/// Resolve an application image relative to the gotify server,
+/// which may be served under a path without a trailing slash.
+fn image_url(gotify_url: &Url, image: &str) -> Result<Url> {
+    let mut base = gotify_url.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
     }
+    Ok(base.join(image)?)
+}
+
+/// The date of a gotify message as RFC 3339 string.
+/// Only the whole message is serialized with the date format of the gotify API.
+fn raw_date(message: &gotify::models::Message) -> Result<serde_json::Value> {
+    Ok(serde_json::to_value(message)?["date"].take())
 }
 
 pub async fn run(config: config::Config) -> Result<()> {
     let data_dir = &config.matrix.session_dir;
     let session_file = data_dir.join("session");
+    tokio::fs::create_dir_all(data_dir).await?;
+    let store = store::Store::open(&data_dir.join("store.sqlite3"))?;
 
-    let (client, last_id) = if session_file.exists() {
-        session::restore_session(&session_file).await?
+    let session_key = config.matrix.session_key()?;
+    let mut client = if session_file.exists() {
+        let (client, legacy_last_id) =
+            session::restore_session(&session_file, session_key.as_deref()).await?;
+        if let Some(last_id) = legacy_last_id {
+            store.migrate_last_id(last_id)?;
+            session::take_last_id(&session_file, session_key.as_deref()).await?;
+        }
+        client
     } else {
-        (
-            session::login(&config, &data_dir, &session_file).await?,
-            None,
-        )
+        session::login(&config, &data_dir, &session_file).await?
     };
 
-    let gotify_client: GotifyClient =
-        gotify::Client::new(config.gotify.url.as_str(), &config.gotify.token)?;
-    sync(client, config, gotify_client, last_id)
-        .await
-        .map_err(Into::into)
+    let mut announcement = None;
+    let mut backoff = MIN_BACKOFF;
+    let mut logged_in_at: Option<Instant> = None;
+    loop {
+        match sync(client, &config, &store, announcement.take()).await {
+            Err(e) if session::is_unknown_token(&e) => {
+                // back off if the new session is invalidated right away as well
+                if logged_in_at.map_or(false, |t| t.elapsed() < MAX_BACKOFF) {
+                    warn!("The session was invalidated again, logging in again in {backoff:?}");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                } else {
+                    warn!("The session was invalidated, logging in again");
+                    backoff = MIN_BACKOFF;
+                }
+                let (new_client, old_device_id) = session::relogin(
+                    &config,
+                    &session_file,
+                    session_key.as_deref(),
+                    session::is_soft_logout(&e),
+                )
+                .await?;
+                logged_in_at = Some(Instant::now());
+                client = new_client;
+                announcement = Some(match client.device_id() {
+                    Some(device_id) if *device_id == *old_device_id => {
+                        format!("gotify2matrix logged in again with device {}", device_id)
+                    }
+                    Some(device_id) => format!(
+                        "gotify2matrix logged in again, the device changed from {} to {}",
+                        old_device_id, device_id
+                    ),
+                    None => "gotify2matrix logged in again".to_string(),
+                });
+            }
+            result => return result,
+        }
+    }
 }
 
 async fn sync_gotify_messages(
     client: MatrixClient,
     gotify_client: GotifyClient,
     config: config::Config,
+    store: store::Store,
+    outbox: Outbox,
     last_id: Option<i64>,
 ) -> Result<()> {
     info!("Syncing gotify messages...");
 
     let mut current_id = last_id;
     loop {
-        match sync_gotify_messages_loop(&client, &gotify_client, &config, &mut current_id).await {
+        match sync_gotify_messages_loop(
+            &client,
+            &gotify_client,
+            &config,
+            &store,
+            &outbox,
+            &mut current_id,
+        )
+        .await
+        {
             Ok(_) => {}
             Err(e) => {
                 warn!("Error {:?} in sync_gotify_messages_loop", e);
@@ -169,18 +309,64 @@ async fn sync_gotify_messages_loop(
     client: &MatrixClient,
     gotify_client: &GotifyClient,
     config: &config::Config,
+    store: &store::Store,
+    outbox: &Outbox,
     last_id: &mut Option<i64>,
 ) -> Result<()> {
     debug!("Syncing gotify messages with last_id: {:?}", last_id);
-    let room = client
-        .get_room(
-            <&RoomId>::try_from(config.matrix.room_id.as_str()).expect("Could not convert room id"),
-        )
-        .unwrap();
-    // get applications
-    let converter = Converter::new(gotify_client, config).await?;
+    let dead_letter_file = config.matrix.session_dir.join("dead_letter.jsonl");
+    let mut forwarder = Forwarder {
+        client,
+        gotify_client,
+        // get applications
+        converter: Converter::new(gotify_client, config).await?,
+        default_room: RoomId::parse(&config.matrix.room_id)?,
+        gotify_url: &config.gotify.url,
+        store,
+        dead_letter_file: &dead_letter_file,
+        // queue messages before sending them if they are deleted from gotify
+        outbox: config.gotify.delete_sent.then_some(outbox),
+        delete: config.gotify.delete_sent
+            && config.gotify.delete_after == config::DeleteAfter::Queued,
+        max_retries: config.gotify.max_retries,
+    };
+
+    // open the stream first, so that messages arriving while the backlog is sent are not missed
+    let mut msg_stream = gotify_client.stream_messages().await?;
+    let mut buffered = VecDeque::new();
+    {
+        let backlog = forwarder.forward_backlog(last_id);
+        tokio::pin!(backlog);
+        loop {
+            tokio::select! {
+                result = &mut backlog => break result?,
+                Some(result) = msg_stream.next() => buffered.push_back(result?),
+            }
+        }
+    }
 
-    // retrieve all old messages
+    debug!(
+        "Sending {} messages received during backlog",
+        buffered.len()
+    );
+    for msg in buffered {
+        forwarder.forward(&msg, last_id).await?;
+    }
+
+    // stream messages
+    while let Some(result) = msg_stream.next().await {
+        let msg = result?;
+        forwarder.forward(&msg, last_id).await?;
+    }
+
+    Ok(())
+}
+
+/// Retrieve all messages newer than `last_id`, oldest first.
+async fn fetch_backlog(
+    gotify_client: &GotifyClient,
+    last_id: Option<i64>,
+) -> Result<Vec<gotify::models::Message>> {
     let mut msg_builder = gotify_client.get_messages();
     let mut paged_msgs = msg_builder.send().await?;
     let mut msgs: Vec<_> = paged_msgs
@@ -205,58 +391,180 @@ async fn sync_gotify_messages_loop(
     }
 
     msgs.reverse();
+    Ok(msgs)
+}
 
-    // send old messages
-    let session_file = &config.matrix.session_dir.join("session");
-    for msg in msgs {
-        let message = converter.convert(&msg)?;
-        send_and_delete(
-            gotify_client,
-            message,
-            msg.id,
-            &room,
-            last_id,
-            session_file,
-            config.gotify.delete_sent,
-        )
-        .await?;
+struct Forwarder<'a> {
+    client: &'a MatrixClient,
+    gotify_client: &'a GotifyClient,
+    converter: Converter<'a>,
+    default_room: OwnedRoomId,
+    gotify_url: &'a Url,
+    store: &'a store::Store,
+    dead_letter_file: &'a Path,
+    outbox: Option<&'a Outbox>,
+    // delete messages from gotify once they are handled here
+    delete: bool,
+    max_retries: u32,
+}
+
+impl Forwarder<'_> {
+    /// Send all old messages.
+    async fn forward_backlog(&mut self, last_id: &mut Option<i64>) -> Result<()> {
+        let msgs = fetch_backlog(self.gotify_client, *last_id).await?;
+        for msg in msgs {
+            self.forward(&msg, last_id).await?;
+        }
+        Ok(())
     }
 
-    // stream messages
-    let mut msg_stream = gotify_client.stream_messages().await?;
-    while let Some(result) = msg_stream.next().await {
-        let msg = result?;
-        let message = converter.convert(&msg)?;
-        send_and_delete(
-            gotify_client,
-            message,
+    /// Forward a gotify message and mark it as delivered.
+    /// Messages up to `last_id` were already delivered and are skipped.
+    /// Messages that can not be rendered, or that still fail after `max_retries` for other reasons
+    /// than matrix being unreachable, are written to the dead-letter file.
+    /// While matrix is unreachable they are retried until it is back.
+    async fn forward(
+        &mut self,
+        msg: &gotify::models::Message,
+        last_id: &mut Option<i64>,
+    ) -> Result<()> {
+        if msg.id <= last_id.unwrap_or(0) {
+            debug!("Skipping already delivered message with id {}", msg.id);
+            return Ok(());
+        }
+
+        let delivery = match self.converter.convert(msg).await {
+            Ok(Some((room_id, message))) => {
+                self.deliver_with_retries(msg, &room_id, message).await?
+            }
+            Ok(None) => {
+                debug!("Dropping message with id {}", msg.id);
+                Delivery::Dropped
+            }
+            Err(e) => {
+                self.dead_letter(msg, e).await?;
+                Delivery::DeadLettered
+            }
+        };
+
+        persist_and_delete(
+            self.gotify_client,
+            self.store,
             msg.id,
-            &room,
+            delivery,
             last_id,
-            session_file,
-            config.gotify.delete_sent,
+            self.delete,
         )
-        .await?;
+        .await
     }
 
-    Ok(())
+    /// Deliver a converted message, backing off exponentially while matrix is unavailable.
+    async fn deliver_with_retries(
+        &mut self,
+        msg: &gotify::models::Message,
+        room_id: &RoomId,
+        message: RoomMessageEventContent,
+    ) -> Result<Delivery> {
+        let txn_id = transaction_id(self.gotify_url, msg.id);
+        let mut backoff = MIN_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            match self
+                .deliver(msg.id, room_id, message.clone(), &txn_id)
+                .await
+            {
+                Ok(delivery) => return Ok(delivery),
+                Err(e) if !is_unreachable(&e) && attempt >= self.max_retries => {
+                    self.dead_letter(msg, e).await?;
+                    return Ok(Delivery::DeadLettered);
+                }
+                Err(e) => {
+                    if !is_unreachable(&e) {
+                        attempt += 1;
+                    }
+                    warn!(
+                        "Error {:?} forwarding message with id {}, retrying in {:?}",
+                        e, msg.id, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Queue a message in the outbox if enabled, otherwise send it right away with `txn_id`.
+    /// Queued messages are sent with the transaction id of their gotify message id.
+    async fn deliver(
+        &self,
+        id: i64,
+        room_id: &RoomId,
+        message: RoomMessageEventContent,
+        txn_id: &TransactionId,
+    ) -> Result<Delivery> {
+        match self.outbox {
+            Some(outbox) => {
+                outbox.enqueue(id, room_id, &message)?;
+                Ok(Delivery::Queued)
+            }
+            None => {
+                let room = resolve_room(self.client, room_id, &self.default_room).await?;
+                debug!("Send message with id {}", id);
+                let response = room.send(message).with_transaction_id(txn_id).await?;
+                self.store
+                    .record_event(id, room.room_id(), &response.event_id)?;
+                Ok(Delivery::Sent)
+            }
+        }
+    }
+
+    /// Write an undeliverable message to the dead-letter file and try to send it as plain text.
+    async fn dead_letter(&mut self, msg: &gotify::models::Message, error: Error) -> Result<()> {
+        warn!(
+            "Giving up on message with id {}, writing it to {}: {:?}",
+            msg.id,
+            self.dead_letter_file.display(),
+            error
+        );
+        dead_letter::write(self.dead_letter_file, msg, &error).await?;
+
+        let app = self.converter.apps.name(msg.appid).await;
+        let fallback = RoomMessageEventContent::text_plain(format!(
+            "{}: {}\n{}",
+            app,
+            msg.title.as_deref().unwrap_or_default(),
+            msg.message
+        ));
+        // the rejected message may have used the transaction id of the gotify message already
+        let txn_id = fallback_transaction_id(self.gotify_url, msg.id);
+        if let Err(e) = self
+            .deliver(msg.id, &self.default_room, fallback, &txn_id)
+            .await
+        {
+            warn!(
+                "Could not send plaintext fallback for message with id {}: {:?}",
+                msg.id, e
+            );
+        }
+        Ok(())
+    }
 }
 
-pub async fn send_and_delete(
+/// Remember a message as delivered and delete it from gotify if requested.
+async fn persist_and_delete(
     gotify_client: &GotifyClient,
-    message: RoomMessageEventContent,
+    store: &store::Store,
     id: i64,
-    room: &Room,
+    delivery: Delivery,
     last_id: &mut Option<i64>,
-    session_file: &Path,
     delete: bool,
 ) -> Result<()> {
-    debug!("Send message with id {}", id);
-    room.send(message).await?;
     *last_id = Some(id);
-    session::persist_last_id(&session_file, *last_id).await?;
+    store.mark_delivered(id, delivery)?;
 
     if delete {
+        // the message is only gone from gotify once its delivery is persisted
+        store.flush()?;
         debug!("Deleting message with id {}", id);
         gotify_client.delete_message(id).await?;
     }
@@ -264,12 +572,76 @@ pub async fn send_and_delete(
     Ok(())
 }
 
+/// Transaction id for sending a gotify message.
+/// It only depends on the gotify server and message id, so the homeserver deduplicates
+/// a message that is sent again after a crash.
+pub fn transaction_id(gotify_url: &Url, id: i64) -> OwnedTransactionId {
+    let server: String = format!(
+        "{}_{}{}",
+        gotify_url.host_str().unwrap_or_default(),
+        gotify_url.port_or_known_default().unwrap_or_default(),
+        gotify_url.path()
+    )
+    .chars()
+    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+    .collect();
+    format!("g2m_{}_{}", server.trim_end_matches('_'), id).into()
+}
+
+/// Transaction id for the plaintext fallback of a dead-lettered gotify message.
+pub fn fallback_transaction_id(gotify_url: &Url, id: i64) -> OwnedTransactionId {
+    format!("{}-fallback", transaction_id(gotify_url, id)).into()
+}
+
+/// Look up a room, joining it if we are not a member yet.
+async fn get_or_join_room(client: &MatrixClient, room_id: &RoomId) -> Result<Room> {
+    match client.get_room(room_id) {
+        Some(room) if room.state() == RoomState::Joined => Ok(room),
+        _ => {
+            info!("Joining room {}", room_id);
+            Ok(client.join_room_by_id(room_id).await?)
+        }
+    }
+}
+
+/// Resolve the target room of a message, falling back to the default room if it is unavailable.
+pub async fn resolve_room(
+    client: &MatrixClient,
+    room_id: &RoomId,
+    default_room: &RoomId,
+) -> Result<Room> {
+    match get_or_join_room(client, room_id).await {
+        Ok(room) => Ok(room),
+        Err(e) if room_id != default_room => {
+            warn!(
+                "Could not use room {}: {:?}, falling back to {}",
+                room_id, e, default_room
+            );
+            get_or_join_room(client, default_room).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Join all configured rooms, each one independently of the others.
+async fn join_rooms(client: &MatrixClient, config: &config::Config) {
+    for room_id in config.room_ids() {
+        let result = match RoomId::parse(room_id) {
+            Ok(room_id) => get_or_join_room(client, &room_id).await.map(|_| ()),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!("Could not join room {}: {:?}", room_id, e);
+        }
+    }
+}
+
 /// Setup the client to listen to new messages.
 async fn sync(
     client: MatrixClient,
-    config: config::Config,
-    gotify_client: GotifyClient,
-    last_id: Option<i64>,
+    config: &config::Config,
+    store: &store::Store,
+    announcement: Option<String>,
 ) -> Result<()> {
     info!("Launching a first sync");
 
@@ -286,6 +658,10 @@ async fn sync(
                 break;
             }
             Err(error) => {
+                let error = Error::from(error);
+                if session::is_unknown_token(&error) {
+                    return Err(error);
+                }
                 warn!("An error occurred during initial sync: {error}");
                 warn!("Trying again…");
             }
@@ -293,13 +669,116 @@ async fn sync(
     }
 
     info!("The client is ready!");
+    join_rooms(&client, config).await;
 
-    tokio::spawn(sync_gotify_messages(
-        client.clone(),
-        gotify_client,
-        config,
-        last_id,
-    ));
-    session::sync_loop(client, sync_settings).await?;
+    let default_room = RoomId::parse(&config.matrix.room_id)?;
+    if let Some(announcement) = announcement {
+        let result = async {
+            let room = get_or_join_room(&client, &default_room).await?;
+            room.send(RoomMessageEventContent::text_plain(announcement))
+                .await?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Could not announce the new login: {:?}", e);
+        }
+    }
+
+    let outbox = Outbox::new(store.clone());
+    let sender = outbox::Sender {
+        outbox: outbox.clone(),
+        client: client.clone(),
+        gotify_client: gotify::Client::new(config.gotify.url.as_str(), &config.gotify.token)?,
+        gotify_url: config.gotify.url.clone(),
+        default_room,
+        dead_letter_file: config.matrix.session_dir.join("dead_letter.jsonl"),
+        max_retries: config.gotify.max_retries,
+        delete: config.gotify.delete_sent
+            && config.gotify.delete_after == config::DeleteAfter::Sent,
+    };
+    let gotify_client: GotifyClient =
+        gotify::Client::new(config.gotify.url.as_str(), &config.gotify.token)?;
+
+    let tasks = [
+        tokio::spawn(session::persist_refreshed_tokens(
+            client.clone(),
+            config.matrix.session_dir.join("session"),
+            config.matrix.session_key()?,
+        ))
+        .abort_handle(),
+        tokio::spawn(store.clone().flush_periodically()).abort_handle(),
+        tokio::spawn(sender.run()).abort_handle(),
+        tokio::spawn(sync_gotify_messages(
+            client.clone(),
+            gotify_client,
+            config.clone(),
+            store.clone(),
+            outbox,
+            store.last_id()?,
+        ))
+        .abort_handle(),
+    ];
+    let result = tokio::select! {
+        result = session::sync_loop(client, sync_settings) => result.map_err(Error::from),
+        result = shutdown_signal() => {
+            info!("Shutting down");
+            result
+        }
+    };
+
+    // the tasks use the client, stop them so they can be restarted with a new one
+    for task in tasks {
+        task.abort();
+    }
+    store.flush()?;
+    result
+}
+
+/// Wait for Ctrl-C or SIGTERM.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_date_is_rfc3339() {
+        let message: gotify::models::Message = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "appid": 1,
+            "message": "message",
+            "title": "title",
+            "priority": 5,
+            "date": "2024-01-02T03:04:05Z",
+            "extras": {},
+        }))
+        .unwrap();
+
+        let raw_date = raw_date(&message).unwrap();
+        assert_eq!(raw_date.as_str(), Some("2024-01-02T03:04:05Z"));
+        assert!(date::parse(&raw_date).is_some());
+    }
+
+    #[test]
+    fn image_url_keeps_the_gotify_path() {
+        let image = "image/x.png";
+        for gotify_url in ["https://host/gotify", "https://host/gotify/"] {
+            let url = image_url(&Url::parse(gotify_url).unwrap(), image).unwrap();
+            assert_eq!(url.as_str(), "https://host/gotify/image/x.png");
+        }
+    }
+}
@@ -1,16 +1,34 @@
 use anyhow::{Context, Error, Result, bail};
+use matrix_sdk::ruma::{RoomId, UserId};
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::PathBuf;
 use url::Url;
 use tracing::debug;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub matrix: Matrix,
     pub gotify: Gotify,
+    #[serde(default)]
+    pub route: Vec<Route>,
+    #[serde(default)]
+    pub verify: Verify,
 }
 
 impl Config {
+    /// The default room, the route rooms and the priority rooms.
+    pub fn room_ids(&self) -> impl Iterator<Item = &String> {
+        let gotify = &self.gotify;
+        std::iter::once(&self.matrix.room_id)
+            .chain(self.route.iter().map(|r| &r.room_id))
+            .chain(
+                [&gotify.low, &gotify.normal, &gotify.high]
+                    .into_iter()
+                    .filter_map(|f| f.room_id.as_ref()),
+            )
+    }
 
     pub fn verify(&mut self) -> Result<()> {
         if self.gotify.threshold_high <= self.gotify.threshold_low {
@@ -19,30 +37,210 @@ impl Config {
         if self.gotify.threshold_low < 0 || self.gotify.threshold_high < 0 {
             bail!("Thresholds must be strictly positive.");
         }
+        let matrix = &self.matrix;
+        if matrix.access_token.is_some() != matrix.device_id.is_some() {
+            bail!("access_token and device_id must be set together");
+        }
+        if matrix.access_token.is_some() {
+            UserId::parse(&matrix.username).context(
+                "username must be a full user id like @bot:example.org when using an access token",
+            )?;
+        }
+        if matrix.password.as_deref().map_or(true, str::is_empty)
+            && matrix.password_file.is_none()
+            && matrix.access_token.is_none()
+            && matrix.login_token.is_none()
+        {
+            bail!("One of password, password_file, access_token or login_token must be set");
+        }
+        RoomId::parse(&self.matrix.room_id)
+            .with_context(|| format!("Invalid room id {}", self.matrix.room_id))?;
+        for route in &self.route {
+            if route.apps.is_empty() && route.app_ids.is_empty() {
+                bail!("Route to {} needs at least one app or app id", route.room_id);
+            }
+            RoomId::parse(&route.room_id)
+                .with_context(|| format!("Invalid room id {} in route", route.room_id))?;
+        }
+        for format in [&self.gotify.low, &self.gotify.normal, &self.gotify.high] {
+            if let Some(room_id) = &format.room_id {
+                RoomId::parse(room_id)
+                    .with_context(|| format!("Invalid priority room id {}", room_id))?;
+            }
+        }
+        // compiles all templates, so errors show up at startup
+        let handlebars = crate::templates::build(self)?;
+        let template_names: HashSet<&str> = handlebars
+            .get_templates()
+            .keys()
+            .filter_map(|name| crate::templates::selectable_name(name))
+            .collect();
+        for (i, rule) in self.gotify.rule.iter().enumerate() {
+            if let Some(room_id) = &rule.room_id {
+                RoomId::parse(room_id)
+                    .with_context(|| format!("Invalid room id {} in rule #{}", room_id, i))?;
+            }
+            if let Some(template) = &rule.template {
+                if !template_names.contains(template.as_str()) {
+                    bail!("Unknown template {} in rule #{}", template, i);
+                }
+            }
+        }
+        for (app, app_config) in &self.gotify.app {
+            if let Some(template) = &app_config.template {
+                if !template_names.contains(template.as_str()) {
+                    bail!("Unknown template {} for app {}", template, app);
+                }
+            }
+        }
+        for format in [&self.gotify.low, &self.gotify.normal, &self.gotify.high] {
+            if let Some(template) = &format.template {
+                if !template_names.contains(template.as_str()) {
+                    bail!("Unknown template {} for a priority", template);
+                }
+            }
+        }
+        crate::rules::Rules::new(&self.gotify.rule)?;
+        for user_id in &self.verify.allowed_users {
+            UserId::parse(user_id)
+                .with_context(|| format!("Invalid user id {} in allowed_users", user_id))?;
+        }
         Ok(())
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Verify {
+    // users that may start a verification, defaults to the members of the configured rooms
+    #[serde(default)]
+    pub allowed_users: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
 pub struct Matrix {
     pub homeserver: Url,
     pub username: String,
-    pub password: String,
+    // one of password, password_file, access_token with device_id or login_token is needed
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub access_token: Option<String>,
+    pub device_id: Option<String>,
+    pub login_token: Option<String>,
     pub room_id: String,
     #[serde(default = "default_session")]
     pub session_dir: PathBuf,
+    // key to encrypt the session file with, alternatively read from a file or the OS keyring
+    pub session_key: Option<String>,
+    pub session_key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub session_key_keyring: bool,
+}
+
+// secrets are redacted, the config is logged on startup
+impl fmt::Debug for Matrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Matrix")
+            .field("homeserver", &self.homeserver)
+            .field("username", &self.username)
+            .field("password", &redact(&self.password))
+            .field("password_file", &self.password_file)
+            .field("access_token", &redact(&self.access_token))
+            .field("device_id", &self.device_id)
+            .field("login_token", &redact(&self.login_token))
+            .field("room_id", &self.room_id)
+            .field("session_dir", &self.session_dir)
+            .field("session_key", &redact(&self.session_key))
+            .field("session_key_file", &self.session_key_file)
+            .field("session_key_keyring", &self.session_key_keyring)
+            .finish()
+    }
+}
+
+fn redact(secret: &Option<String>) -> Option<&'static str> {
+    secret.as_ref().map(|_| "<redacted>")
+}
+
+impl Matrix {
+    /// The configured password, an empty `password` counts as unset.
+    pub fn password(&self) -> Result<Option<String>> {
+        if let Some(password) = self.password.as_ref().filter(|p| !p.is_empty()) {
+            return Ok(Some(password.clone()));
+        }
+        if let Some(file) = &self.password_file {
+            let password = std::fs::read_to_string(file)
+                .with_context(|| format!("Could not read password from {}", file.display()))?;
+            return Ok(Some(password.trim_end().to_string()));
+        }
+        Ok(None)
+    }
+
+    pub fn session_key(&self) -> Result<Option<String>> {
+        if let Some(session_key) = &self.session_key {
+            return Ok(Some(session_key.clone()));
+        }
+        if let Some(file) = &self.session_key_file {
+            let session_key = std::fs::read_to_string(file)
+                .with_context(|| format!("Could not read session key from {}", file.display()))?;
+            return Ok(Some(session_key.trim_end().to_string()));
+        }
+        if self.session_key_keyring {
+            return keyring_session_key(&self.username).map(Some);
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_session_key(username: &str) -> Result<String> {
+    keyring::Entry::new("gotify2matrix", username)?
+        .get_password()
+        .context("Could not read session key from keyring")
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_session_key(_username: &str) -> Result<String> {
+    bail!("gotify2matrix was built without keyring support, enable the `keyring` feature")
+}
+
+/// Maps gotify applications (by name or id) to a target room.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Route {
+    #[serde(default)]
+    pub apps: Vec<String>,
+    #[serde(default)]
+    pub app_ids: Vec<i64>,
+    pub room_id: String,
+}
+
+impl Route {
+    pub fn matches(&self, app_id: i64, app_name: &str) -> bool {
+        self.app_ids.contains(&app_id) || self.apps.iter().any(|a| a == app_name)
+    }
 }
 
 fn default_session() -> PathBuf {
     PathBuf::from("./session")
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Gotify {
     pub url: Url,
     pub token: String,
     #[serde(default)]
     pub delete_sent: bool,
+    // when deleted messages are removed from gotify, after being queued locally or sent
+    #[serde(default)]
+    pub delete_after: DeleteAfter,
+    // seconds after which the cached gotify applications are refetched
+    #[serde(default = "default_app_refresh_interval")]
+    pub app_refresh_interval: u64,
+    // retries per message before it is written to the dead-letter file
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    // timezone (e.g. "Europe/Berlin") and strftime format of the date in templates
+    pub date_timezone: Option<String>,
+    #[serde(default = "default_date_format")]
+    pub date_format: String,
 
     // default vaules that can be overriden bei low, normal, high settings
     #[serde(default = "default_plain")]
@@ -61,15 +259,88 @@ pub struct Gotify {
     pub normal: GotifyFormat,
     #[serde(default = "default_format")]
     pub high: GotifyFormat,
+
+    // named templates that can be selected by rules, apps and priorities
+    #[serde(default)]
+    pub templates: HashMap<String, GotifyTemplate>,
+    // directory with more named templates and partials as *.hbs files
+    pub template_dir: Option<PathBuf>,
+    // fail on unknown variables in templates instead of rendering them empty
+    #[serde(default)]
+    pub strict_templates: bool,
+    // rules to filter and rewrite messages, the first matching rule wins
+    #[serde(default)]
+    pub rule: Vec<Rule>,
+    // per app settings, keyed by the gotify application name
+    #[serde(default)]
+    pub app: HashMap<String, GotifyApp>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct GotifyFormat {
     pub plain: Option<String>,
     pub html: Option<String>,
+    pub room_id: Option<String>,
+    // used by the priority_emoji and priority_color template helpers
+    pub emoji: Option<String>,
+    pub color: Option<String>,
+    // named template, takes precedence over plain and html
+    pub template: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct GotifyApp {
+    // render the message as markdown, overrides the content type set by the app
+    pub markdown: Option<bool>,
+    // named template, takes precedence over the priority templates
+    pub template: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteAfter {
+    Queued,
+    #[default]
+    Sent,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GotifyTemplate {
+    pub plain: Option<String>,
+    pub html: Option<String>,
 }
 
-#[derive(Debug)]
+/// A rule matching messages and the actions applied to them.
+/// All set conditions must match, regexes are applied to title and message.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Rule {
+    // conditions
+    pub app: Option<String>,
+    pub priority_min: Option<i32>,
+    pub priority_max: Option<i32>,
+    pub title: Option<String>,
+    pub message: Option<String>,
+
+    // actions
+    #[serde(default)]
+    pub drop: bool,
+    pub priority: Option<GotifyPriority>,
+    pub room_id: Option<String>,
+    pub template: Option<String>,
+}
+
+impl Gotify {
+    pub fn format(&self, prio: &GotifyPriority) -> &GotifyFormat {
+        match prio {
+            GotifyPriority::Low => &self.low,
+            GotifyPriority::Normal => &self.normal,
+            GotifyPriority::High => &self.high,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum GotifyPriority {
     Low,
     Normal,
@@ -87,6 +358,14 @@ impl GotifyPriority {
             GotifyPriority::Normal
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GotifyPriority::Low => "low",
+            GotifyPriority::Normal => "normal",
+            GotifyPriority::High => "high",
+        }
+    }
 }
 
 
@@ -95,16 +374,32 @@ fn default_plain() -> String {
 }
 
 fn default_html() -> String {
-    "<h4>{{app}}: {{title}}</h4>\n{{message}}".to_string()
+    "<h4>{{app}}: {{title}}</h4>\n{{{message_html}}}".to_string()
 }
 
 fn default_format() -> GotifyFormat {
     GotifyFormat {
         plain: None,
         html: None,
+        room_id: None,
+        emoji: None,
+        color: None,
+        template: None,
     }
 }
 
+fn default_app_refresh_interval() -> u64 {
+    600
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_date_format() -> String {
+    "%Y-%m-%d %H:%M:%S".to_string()
+}
+
 fn default_threshold_low() -> i32 {
     3
 }
@@ -133,8 +428,15 @@ impl Config {
             gotify.low = low;
             gotify.normal = normal;
             gotify.high = high;
+            let verify = envy::prefixed("G2M_VERIFY_").from_env::<Verify>()?;
 
-            config = Some(Config { matrix, gotify });
+            // routes can only be configured in the config file
+            config = Some(Config {
+                matrix,
+                gotify,
+                route: Vec::new(),
+                verify,
+            });
         }
 
         let mut config = config.ok_or(Error::msg(
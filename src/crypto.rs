@@ -0,0 +1,107 @@
+use anyhow::{anyhow, bail};
+use matrix_sdk::{
+    config::SyncSettings,
+    ruma::api::client::uiaa::{AuthData, Password, UiaaInfo, UserIdentifier},
+    Client,
+};
+use tracing::info;
+
+use crate::{config, session};
+
+/// Bootstrap cross-signing and server-side key backup for the bot account.
+pub async fn setup(config: config::Config) -> anyhow::Result<()> {
+    let client = session::restore_existing(&config).await?;
+    // upload the device keys before signing them
+    client.sync_once(SyncSettings::default()).await?;
+
+    bootstrap_cross_signing(&client, &config).await?;
+
+    info!("Enabling key backup and recovery");
+    let recovery_key = client
+        .encryption()
+        .recovery()
+        .enable()
+        .wait_for_backups_to_upload()
+        .await?;
+    session::write_recovery_key(&config, &recovery_key).await?;
+
+    println!("Recovery key: {recovery_key}");
+    println!(
+        "The recovery key was stored in '{}', keep a copy in a safe place.",
+        config
+            .matrix
+            .session_dir
+            .join("recovery_key")
+            .to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Recover the cross-signing keys and backup with the recovery key and verify this device.
+pub async fn recover(config: config::Config, recovery_key: Option<String>) -> anyhow::Result<()> {
+    let recovery_key = match recovery_key {
+        Some(recovery_key) => recovery_key,
+        None => session::read_recovery_key(&config)
+            .await
+            .map_err(|e| anyhow!("No recovery key given and none stored: {e}"))?,
+    };
+
+    let client = session::restore_existing(&config).await?;
+    client.sync_once(SyncSettings::default()).await?;
+
+    info!("Recovering secrets from the server-side secret storage");
+    client
+        .encryption()
+        .recovery()
+        .recover(&recovery_key)
+        .await?;
+
+    let Some(device) = client.encryption().get_own_device().await? else {
+        bail!("Own device is unknown");
+    };
+    device.verify().await?;
+    println!("Device {} is now verified", device.device_id());
+    Ok(())
+}
+
+async fn bootstrap_cross_signing(client: &Client, config: &config::Config) -> anyhow::Result<()> {
+    if let Some(status) = client.encryption().cross_signing_status().await {
+        if status.is_complete() {
+            info!("Cross-signing is already set up");
+            return Ok(());
+        }
+    }
+
+    info!("Bootstrapping cross-signing");
+    if let Err(e) = client.encryption().bootstrap_cross_signing(None).await {
+        let Some(info) = e.as_uiaa_response() else {
+            return Err(e.into());
+        };
+        let auth = password_auth(client, config, info)?;
+        client
+            .encryption()
+            .bootstrap_cross_signing(Some(auth))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Answer a user-interactive authentication request with the configured password.
+pub fn password_auth(
+    client: &Client,
+    config: &config::Config,
+    info: &UiaaInfo,
+) -> anyhow::Result<AuthData> {
+    let Some(password) = config.matrix.password()? else {
+        bail!("The homeserver requires a password, set password or password_file");
+    };
+    let user_id = client
+        .user_id()
+        .ok_or_else(|| anyhow!("Client is not logged in"))?;
+    let mut auth = Password::new(
+        UserIdentifier::UserIdOrLocalpart(user_id.to_string()),
+        password,
+    );
+    auth.session = info.session.clone();
+    Ok(AuthData::Password(auth))
+}
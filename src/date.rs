@@ -0,0 +1,58 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, FixedOffset, Local,
+};
+use chrono_tz::Tz;
+
+use crate::config;
+
+/// Formats gotify message dates in the configured timezone.
+#[derive(Clone, Debug)]
+pub struct DateFormat {
+    timezone: Option<Tz>,
+    format: String,
+}
+
+impl DateFormat {
+    pub fn new(config: &config::Gotify) -> Result<DateFormat> {
+        let timezone = config
+            .date_timezone
+            .as_deref()
+            .map(|tz| {
+                tz.parse::<Tz>()
+                    .map_err(|e| anyhow!("Invalid timezone {}: {}", tz, e))
+            })
+            .transpose()?;
+        if !is_valid(&config.date_format) {
+            bail!("Invalid date format {}", config.date_format);
+        }
+        Ok(DateFormat {
+            timezone,
+            format: config.date_format.clone(),
+        })
+    }
+
+    /// Format a date in the configured timezone, the local one if none is set.
+    pub fn format(&self, date: &DateTime<FixedOffset>) -> String {
+        self.format_with(date, &self.format)
+    }
+
+    /// Format a date in the configured timezone with a format checked by `is_valid`.
+    pub fn format_with(&self, date: &DateTime<FixedOffset>, format: &str) -> String {
+        match &self.timezone {
+            Some(tz) => date.with_timezone(tz).format(format).to_string(),
+            None => date.with_timezone(&Local).format(format).to_string(),
+        }
+    }
+}
+
+/// Whether the strftime format can be used, formatting with an invalid one panics.
+pub fn is_valid(format: &str) -> bool {
+    !StrftimeItems::new(format).any(|item| item == Item::Error)
+}
+
+/// Parse a date as serialized by gotify (RFC 3339).
+pub fn parse(date: &serde_json::Value) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(date.as_str()?).ok()
+}
@@ -0,0 +1,33 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::Path;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+/// A message that could not be delivered, together with the reason.
+#[derive(Serialize)]
+struct DeadLetter<'a, T: Serialize> {
+    message: &'a T,
+    error: String,
+}
+
+/// Append a message to the dead-letter file, one JSON object per line.
+pub async fn write(
+    dead_letter_file: &Path,
+    message: &impl Serialize,
+    error: &anyhow::Error,
+) -> Result<()> {
+    let mut line = serde_json::to_string(&DeadLetter {
+        message,
+        error: format!("{:#}", error),
+    })?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dead_letter_file)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    file.flush().await?;
+    Ok(())
+}
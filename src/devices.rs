@@ -0,0 +1,109 @@
+use anyhow::{anyhow, bail};
+use matrix_sdk::{config::SyncSettings, ruma::DeviceId};
+use tracing::info;
+
+use crate::{config, crypto, session, store, verify};
+
+/// List the devices of the bot account with their verification state.
+pub async fn list(config: config::Config) -> anyhow::Result<()> {
+    let client = session::restore_existing(&config).await?;
+    // fetch the device keys to know the verification state
+    client.sync_once(SyncSettings::default()).await?;
+
+    let user_id = client
+        .user_id()
+        .ok_or_else(|| anyhow!("Client is not logged in"))?;
+    let own_device_id = client
+        .device_id()
+        .ok_or_else(|| anyhow!("Client is not logged in"))?;
+
+    println!("Devices of user {user_id}");
+    for device in client.devices().await?.devices {
+        let verified = client
+            .encryption()
+            .get_device(user_id, &device.device_id)
+            .await?
+            .is_some_and(|d| d.is_verified());
+        let display_name = device.display_name.as_deref().unwrap_or("-");
+        let display_name = if device.device_id.as_str() == own_device_id.as_str() {
+            format!("{display_name} (this device)")
+        } else {
+            display_name.to_string()
+        };
+        verify::print_device(&device.device_id, Some(&display_name), verified);
+    }
+    Ok(())
+}
+
+/// Rename a device, the current device if none is given.
+pub async fn rename(
+    config: config::Config,
+    device_id: Option<String>,
+    name: String,
+) -> anyhow::Result<()> {
+    let client = session::restore_existing(&config).await?;
+    let device_id = match &device_id {
+        Some(device_id) => <&DeviceId>::from(device_id.as_str()),
+        None => client
+            .device_id()
+            .ok_or_else(|| anyhow!("Client is not logged in"))?,
+    };
+
+    client.rename_device(device_id, &name).await?;
+    println!("Renamed device {device_id} to {name}");
+    Ok(())
+}
+
+/// Delete other devices of the bot account, authenticating with the configured password.
+pub async fn delete(config: config::Config, device_ids: Vec<String>) -> anyhow::Result<()> {
+    let client = session::restore_existing(&config).await?;
+    let device_ids: Vec<_> = device_ids
+        .iter()
+        .map(|d| <&DeviceId>::from(d.as_str()).to_owned())
+        .collect();
+    if let Some(own_device_id) = client.device_id() {
+        if device_ids
+            .iter()
+            .any(|d| d.as_str() == own_device_id.as_str())
+        {
+            bail!("Refusing to delete the current device {own_device_id}, use logout instead");
+        }
+    }
+
+    if let Err(e) = client.delete_devices(&device_ids, None).await {
+        let Some(info) = e.as_uiaa_response() else {
+            return Err(e.into());
+        };
+        let auth = crypto::password_auth(&client, &config, info)?;
+        client.delete_devices(&device_ids, Some(auth)).await?;
+    }
+
+    for device_id in &device_ids {
+        println!("Deleted device {device_id}");
+    }
+    Ok(())
+}
+
+/// Invalidate the access token and remove the session directory.
+pub async fn logout(config: config::Config) -> anyhow::Result<()> {
+    let session_dir = &config.matrix.session_dir;
+    let store_file = session_dir.join("store.sqlite3");
+    if store_file.exists() && store::Store::open(&store_file)?.peek()?.is_some() {
+        bail!(
+            "The outbox still contains messages, run gotify2matrix to send them before logging out"
+        );
+    }
+
+    let client = session::restore_existing(&config).await?;
+    if let Some(device_id) = client.device_id() {
+        info!("Logging out device {device_id}");
+    }
+    client.matrix_auth().logout().await?;
+
+    session::remove(&config).await?;
+    println!(
+        "Logged out and removed the session from '{}'",
+        session_dir.to_string_lossy()
+    );
+    Ok(())
+}
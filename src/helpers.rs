@@ -0,0 +1,240 @@
+use chrono::{DateTime, Duration, Utc};
+use handlebars::{
+    handlebars_helper, Context, Handlebars, Helper, HelperDef, HelperResult, JsonRender, JsonValue,
+    Output, RenderContext, RenderError, ScopedJson,
+};
+use regex::Regex;
+
+use crate::{config, date};
+
+/// Register the helpers available in all templates.
+pub fn register(handlebars: &mut Handlebars, config: &config::Gotify) -> anyhow::Result<()> {
+    handlebars.register_helper("truncate", Box::new(truncate));
+    handlebars.register_helper("upper", Box::new(upper));
+    handlebars.register_helper("lower", Box::new(lower));
+    handlebars.register_helper("default", Box::new(DefaultValue));
+    handlebars.register_helper("relative_time", Box::new(relative_time));
+    handlebars.register_helper(
+        "format_date",
+        Box::new(FormatDate(date::DateFormat::new(config)?)),
+    );
+    handlebars.register_helper(
+        "priority_emoji",
+        Box::new(PriorityStyle::new(
+            config,
+            |f| f.emoji.as_deref(),
+            ["🔵", "🟡", "🔴"],
+        )),
+    );
+    handlebars.register_helper(
+        "priority_color",
+        Box::new(PriorityStyle::new(
+            config,
+            |f| f.color.as_deref(),
+            ["#2e86de", "#f39c12", "#e74c3c"],
+        )),
+    );
+    handlebars.register_helper("code", Box::new(code));
+    handlebars.register_helper("json", Box::new(json));
+    handlebars.register_helper("replace", Box::new(Replace));
+    Ok(())
+}
+
+handlebars_helper!(upper: |s: str| s.to_uppercase());
+handlebars_helper!(lower: |s: str| s.to_lowercase());
+handlebars_helper!(json: |value: Json| serde_json::to_string_pretty(value).unwrap_or_default());
+
+// shorten to at most `len` characters, marking the cut with an ellipsis
+handlebars_helper!(truncate: |s: str, len: u64| {
+    let len = len as usize;
+    if s.chars().count() <= len {
+        s.to_string()
+    } else {
+        let mut truncated: String = s.chars().take(len.saturating_sub(1)).collect();
+        truncated.push('…');
+        truncated
+    }
+});
+
+// e.g. "5 minutes ago" for an RFC 3339 timestamp, the timestamp itself if it can't be parsed
+handlebars_helper!(relative_time: |timestamp: str| {
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(date) => relative(Utc::now().signed_duration_since(date)),
+        Err(_) => timestamp.to_string(),
+    }
+});
+
+fn relative(elapsed: Duration) -> String {
+    let seconds = elapsed.num_seconds();
+    let (amount, unit) = match seconds.abs() {
+        s if s < 60 => return "just now".to_string(),
+        s if s < 60 * 60 => (s / 60, "minute"),
+        s if s < 24 * 60 * 60 => (s / (60 * 60), "hour"),
+        s => (s / (24 * 60 * 60), "day"),
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+    if seconds < 0 {
+        format!("in {} {}{}", amount, unit, plural)
+    } else {
+        format!("{} {}{} ago", amount, unit, plural)
+    }
+}
+
+/// The first parameter unless it is missing, null, false or empty, the second otherwise.
+struct DefaultValue;
+
+impl HelperDef for DefaultValue {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let value = h.param(0).map(|p| p.value()).unwrap_or(&JsonValue::Null);
+        let empty = match value {
+            JsonValue::Null | JsonValue::Bool(false) => true,
+            JsonValue::String(s) => s.is_empty(),
+            _ => false,
+        };
+        let result = if empty {
+            h.param(1).map(|p| p.value()).unwrap_or(&JsonValue::Null)
+        } else {
+            value
+        };
+        Ok(ScopedJson::Derived(result.clone()))
+    }
+}
+
+/// Format an RFC 3339 timestamp in the configured timezone, optionally with a custom format.
+struct FormatDate(date::DateFormat);
+
+impl HelperDef for FormatDate {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let timestamp = h
+            .param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new("`format_date` helper: timestamp must be a string"))?;
+        let date = DateTime::parse_from_rfc3339(timestamp)
+            .map_err(|e| RenderError::from_error("`format_date` helper: invalid timestamp", e))?;
+        let formatted = match h.param(1).and_then(|p| p.value().as_str()) {
+            Some(format) if !date::is_valid(format) => {
+                return Err(RenderError::new(format!(
+                    "`format_date` helper: invalid format {}",
+                    format
+                )));
+            }
+            Some(format) => self.0.format_with(&date, format),
+            None => self.0.format(&date),
+        };
+        Ok(ScopedJson::Derived(JsonValue::from(formatted)))
+    }
+}
+
+/// A configured value per priority tier, looked up by numeric priority or tier name.
+struct PriorityStyle {
+    threshold_low: i32,
+    threshold_high: i32,
+    low: String,
+    normal: String,
+    high: String,
+}
+
+impl PriorityStyle {
+    fn new(
+        config: &config::Gotify,
+        value: impl Fn(&config::GotifyFormat) -> Option<&str>,
+        defaults: [&str; 3],
+    ) -> PriorityStyle {
+        PriorityStyle {
+            threshold_low: config.threshold_low,
+            threshold_high: config.threshold_high,
+            low: value(&config.low).unwrap_or(defaults[0]).to_string(),
+            normal: value(&config.normal).unwrap_or(defaults[1]).to_string(),
+            high: value(&config.high).unwrap_or(defaults[2]).to_string(),
+        }
+    }
+}
+
+impl HelperDef for PriorityStyle {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        use config::GotifyPriority;
+        let value = h.param(0).map(|p| p.value());
+        let prio = match value {
+            Some(JsonValue::Number(priority)) => priority.as_i64().map(|p| {
+                GotifyPriority::from_thresholds(p as i32, self.threshold_low, self.threshold_high)
+            }),
+            Some(name @ JsonValue::String(_)) => serde_json::from_value(name.clone()).ok(),
+            _ => None,
+        }
+        .ok_or_else(|| {
+            RenderError::new(format!(
+                "`{}` helper: expected a priority or priority name, got {:?}",
+                h.name(),
+                value
+            ))
+        })?;
+        let result = match prio {
+            GotifyPriority::Low => &self.low,
+            GotifyPriority::Normal => &self.normal,
+            GotifyPriority::High => &self.high,
+        };
+        Ok(ScopedJson::Derived(JsonValue::from(result.clone())))
+    }
+}
+
+/// Wrap the escaped value in a code block, meant for html templates.
+fn code(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = h.param(0).map(|p| p.value().render()).unwrap_or_default();
+    out.write(&format!(
+        "<pre><code>{}</code></pre>",
+        handlebars::html_escape(&value)
+    ))?;
+    Ok(())
+}
+
+/// Replace all matches of a regex, e.g. `{{replace message "[0-9]+" "#"}}`.
+struct Replace;
+
+impl HelperDef for Replace {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let param = |i: usize| {
+            h.param(i).and_then(|p| p.value().as_str()).ok_or_else(|| {
+                RenderError::new(format!(
+                    "`replace` helper: parameter {} must be a string",
+                    i
+                ))
+            })
+        };
+        let (value, pattern, replacement) = (param(0)?, param(1)?, param(2)?);
+        let regex = Regex::new(pattern)
+            .map_err(|e| RenderError::from_error("`replace` helper: invalid regex", e))?;
+        Ok(ScopedJson::Derived(JsonValue::from(
+            regex.replace_all(value, replacement).into_owned(),
+        )))
+    }
+}
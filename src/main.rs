@@ -1,15 +1,91 @@
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+mod apps;
 mod client;
 pub mod config;
+mod crypto;
+mod date;
+mod dead_letter;
+mod devices;
+mod helpers;
+mod markdown;
+mod outbox;
+mod rules;
 pub mod session;
+mod store;
+mod templates;
 mod verify;
 
 #[derive(StructOpt, Clone)]
 enum Command {
-    #[structopt(about = "Wait for incoming device verifications")]
-    Verify,
+    #[structopt(about = "Wait for incoming device verifications or request one")]
+    Verify {
+        #[structopt(
+            long,
+            help = "Confirm in the room instead of on the terminal and exit once done"
+        )]
+        headless: bool,
+        #[structopt(long, help = "Request a verification from this user and exit once done")]
+        user: Option<String>,
+        #[structopt(long, requires = "user", help = "Only request it from this device")]
+        device: Option<String>,
+    },
+    #[structopt(about = "Inspect the message rules")]
+    Rules(RulesCommand),
+    #[structopt(about = "Manage the session file")]
+    Session(SessionCommand),
+    #[structopt(about = "Bootstrap cross-signing and key backup, prints a recovery key")]
+    SetupCrypto,
+    #[structopt(about = "Manage the devices of the bot account")]
+    Devices(DevicesCommand),
+    #[structopt(about = "Log out and remove the session file and matrix store")]
+    Logout,
+    #[structopt(about = "Verify this device with the recovery key")]
+    Recover {
+        #[structopt(long, help = "Defaults to the recovery key stored by setup-crypto")]
+        recovery_key: Option<String>,
+    },
+}
+
+#[derive(StructOpt, Clone)]
+enum DevicesCommand {
+    #[structopt(about = "List the devices with their verification state")]
+    List,
+    #[structopt(about = "Rename a device, the current one by default")]
+    Rename {
+        #[structopt(long)]
+        device: Option<String>,
+        name: String,
+    },
+    #[structopt(about = "Delete devices, authenticating with the configured password")]
+    Delete {
+        #[structopt(required = true)]
+        devices: Vec<String>,
+    },
+}
+
+#[derive(StructOpt, Clone)]
+enum SessionCommand {
+    #[structopt(about = "Encrypt the session file with the configured session key")]
+    Encrypt,
+    #[structopt(about = "Decrypt the session file with the configured session key")]
+    Decrypt,
+}
+
+#[derive(StructOpt, Clone)]
+enum RulesCommand {
+    #[structopt(about = "Evaluate a sample message against the rules")]
+    Test {
+        #[structopt(long)]
+        app: String,
+        #[structopt(long, default_value = "5")]
+        priority: i32,
+        #[structopt(long, default_value = "")]
+        title: String,
+        #[structopt(long, default_value = "")]
+        message: String,
+    },
 }
 
 #[derive(StructOpt)]
@@ -35,7 +111,33 @@ async fn main() -> anyhow::Result<()> {
     };
     let config = config::Config::read(config_file)?;
     match options.command {
-        Some(Command::Verify) => verify::run(config).await?,
+        Some(Command::Verify {
+            headless,
+            user,
+            device,
+        }) => verify::run(config, headless, user, device).await?,
+        Some(Command::Rules(RulesCommand::Test {
+            app,
+            priority,
+            title,
+            message,
+        })) => rules::test(config, &app, priority, &title, &message)?,
+        Some(Command::Session(SessionCommand::Encrypt)) => {
+            session::convert_session(&config, true).await?
+        }
+        Some(Command::Session(SessionCommand::Decrypt)) => {
+            session::convert_session(&config, false).await?
+        }
+        Some(Command::Devices(DevicesCommand::List)) => devices::list(config).await?,
+        Some(Command::Devices(DevicesCommand::Rename { device, name })) => {
+            devices::rename(config, device, name).await?
+        }
+        Some(Command::Devices(DevicesCommand::Delete { devices })) => {
+            devices::delete(config, devices).await?
+        }
+        Some(Command::Logout) => devices::logout(config).await?,
+        Some(Command::SetupCrypto) => crypto::setup(config).await?,
+        Some(Command::Recover { recovery_key }) => crypto::recover(config, recovery_key).await?,
         _ => client::run(config).await?,
     }
     Ok(())
@@ -0,0 +1,21 @@
+use pulldown_cmark::{html, Options, Parser};
+
+/// Whether the sending gotify app marked the message as markdown.
+pub fn is_markdown(message: &gotify::models::Message) -> bool {
+    message
+        .extras
+        .as_ref()
+        .and_then(|extras| extras.get("client::display"))
+        .and_then(|display| display.get("contentType"))
+        .and_then(|content_type| content_type.as_str())
+        == Some("text/markdown")
+}
+
+/// Render markdown to HTML, sanitized so it can be embedded in a matrix message.
+pub fn to_html(markdown: &str) -> String {
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut output = String::new();
+    html::push_html(&mut output, Parser::new_ext(markdown, options));
+    ammonia::clean(&output)
+}
@@ -0,0 +1,243 @@
+use crate::{
+    client::{fallback_transaction_id, resolve_room, transaction_id},
+    dead_letter,
+    store::{Delivery, OutboxEntry, Store},
+};
+use anyhow::Result;
+use gotify::ClientClient as GotifyClient;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId, RoomId},
+    Client as MatrixClient,
+};
+use serde::Serialize;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+use url::Url;
+
+pub const MIN_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Durable queue of messages that still have to be sent to matrix.
+#[derive(Clone)]
+pub struct Outbox {
+    store: Store,
+    notify: Arc<Notify>,
+}
+
+impl Outbox {
+    pub fn new(store: Store) -> Outbox {
+        Outbox {
+            store,
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn enqueue(
+        &self,
+        gotify_id: i64,
+        room_id: &RoomId,
+        content: &RoomMessageEventContent,
+    ) -> Result<()> {
+        debug!("Queueing message with id {}", gotify_id);
+        self.store.enqueue(gotify_id, room_id, content)?;
+        self.notify.notify_one();
+        Ok(())
+    }
+}
+
+pub struct Sender {
+    pub outbox: Outbox,
+    pub client: MatrixClient,
+    pub gotify_client: GotifyClient,
+    pub gotify_url: Url,
+    pub default_room: OwnedRoomId,
+    pub dead_letter_file: PathBuf,
+    pub max_retries: u32,
+    // delete messages from gotify once they are sent
+    pub delete: bool,
+}
+
+impl Sender {
+    /// Drain the outbox in order, backing off exponentially while matrix is unreachable.
+    /// Messages that fail for other reasons more than `max_retries` times are dead-lettered,
+    /// as are messages that can not be decoded.
+    pub async fn run(self) {
+        let mut backoff = MIN_BACKOFF;
+        let mut attempts = 0;
+        loop {
+            let stored = match self.outbox.store.peek() {
+                Ok(Some(stored)) => stored,
+                Ok(None) => {
+                    self.outbox.notify.notified().await;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Could not read outbox: {:?}", e);
+                    tokio::time::sleep(MAX_BACKOFF).await;
+                    continue;
+                }
+            };
+            let entry = match stored.decode() {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!(
+                        "Could not decode queued message with id {}",
+                        stored.gotify_id
+                    );
+                    // the plain body can often still be read from the raw content
+                    let body = serde_json::from_str::<serde_json::Value>(&stored.content)
+                        .ok()
+                        .and_then(|content| content["body"].as_str().map(ToOwned::to_owned));
+                    if !self.dead_letter(stored.gotify_id, &stored, &e, body).await {
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        continue;
+                    }
+                    backoff = MIN_BACKOFF;
+                    attempts = 0;
+                    self.finish(stored.gotify_id, Delivery::DeadLettered).await;
+                    continue;
+                }
+            };
+
+            match self.send(&entry).await {
+                Ok(()) => {
+                    backoff = MIN_BACKOFF;
+                    attempts = 0;
+                    self.finish(entry.gotify_id, Delivery::Sent).await;
+                }
+                Err(e) if !is_unreachable(&e) && attempts >= self.max_retries => {
+                    let body = entry.content.body().to_owned();
+                    if !self
+                        .dead_letter(entry.gotify_id, &entry, &e, Some(body))
+                        .await
+                    {
+                        tokio::time::sleep(backoff).await;
+                        continue;
+                    }
+                    backoff = MIN_BACKOFF;
+                    attempts = 0;
+                    self.finish(entry.gotify_id, Delivery::DeadLettered).await;
+                }
+                Err(e) => {
+                    if !is_unreachable(&e) {
+                        attempts += 1;
+                    }
+                    warn!(
+                        "Error {:?} sending queued message with id {}, retrying in {:?}",
+                        e, entry.gotify_id, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Write an undeliverable message to the dead-letter file and try to send `body` as plain text.
+    /// Returns whether the dead letter was written.
+    async fn dead_letter(
+        &self,
+        gotify_id: i64,
+        message: &impl Serialize,
+        error: &anyhow::Error,
+        body: Option<String>,
+    ) -> bool {
+        warn!(
+            "Giving up on queued message with id {}, writing it to {}: {:?}",
+            gotify_id,
+            self.dead_letter_file.display(),
+            error
+        );
+        if let Err(e) = dead_letter::write(&self.dead_letter_file, message, error).await {
+            warn!("Could not write dead letter: {:?}", e);
+            return false;
+        }
+
+        if let Some(body) = body {
+            if let Err(e) = self.send_fallback(gotify_id, body).await {
+                warn!(
+                    "Could not send plaintext fallback for message with id {}: {:?}",
+                    gotify_id, e
+                );
+            }
+        }
+        true
+    }
+
+    async fn send_fallback(&self, gotify_id: i64, body: String) -> Result<()> {
+        let room = resolve_room(&self.client, &self.default_room, &self.default_room).await?;
+        let response = room
+            .send(RoomMessageEventContent::text_plain(body))
+            .with_transaction_id(&fallback_transaction_id(&self.gotify_url, gotify_id))
+            .await?;
+        self.outbox
+            .store
+            .record_event(gotify_id, room.room_id(), &response.event_id)?;
+        Ok(())
+    }
+
+    async fn send(&self, entry: &OutboxEntry) -> Result<()> {
+        let room = resolve_room(&self.client, &entry.room_id, &self.default_room).await?;
+        debug!("Send queued message with id {}", entry.gotify_id);
+        let response = room
+            .send(entry.content.clone())
+            .with_transaction_id(&transaction_id(&self.gotify_url, entry.gotify_id))
+            .await?;
+        self.outbox
+            .store
+            .record_event(entry.gotify_id, room.room_id(), &response.event_id)?;
+        Ok(())
+    }
+
+    /// Remove a handled message from the outbox and from gotify if requested.
+    async fn finish(&self, gotify_id: i64, delivery: Delivery) {
+        let store = &self.outbox.store;
+        if let Err(e) = store.remove(gotify_id) {
+            warn!(
+                "Could not remove message with id {} from outbox: {:?}",
+                gotify_id, e
+            );
+        }
+        if let Err(e) = store.mark_delivered(gotify_id, delivery) {
+            warn!(
+                "Could not record delivery of message with id {}: {:?}",
+                gotify_id, e
+            );
+        }
+        if self.delete {
+            // the message is only gone from gotify once its delivery is persisted
+            if let Err(e) = store.flush() {
+                warn!(
+                    "Not deleting message with id {} from gotify, its delivery could not be recorded: {:?}",
+                    gotify_id, e
+                );
+                return;
+            }
+            debug!("Deleting message with id {}", gotify_id);
+            if let Err(e) = self.gotify_client.delete_message(gotify_id).await {
+                warn!(
+                    "Could not delete message with id {} from gotify: {:?}",
+                    gotify_id, e
+                );
+            }
+        }
+    }
+}
+
+/// Whether the homeserver responded with an error, as opposed to being unreachable.
+fn is_rejected(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<matrix_sdk::Error>()
+        .map_or(false, |e| e.as_client_api_error().is_some())
+}
+
+/// Whether matrix could not be reached, as opposed to failing to handle the message.
+/// Only these errors are retried without limit.
+pub fn is_unreachable(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<matrix_sdk::Error>(),
+        Some(matrix_sdk::Error::Http(_))
+    ) && !is_rejected(error)
+}
@@ -0,0 +1,103 @@
+use crate::config;
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+
+struct CompiledRule {
+    rule: config::Rule,
+    title: Option<Regex>,
+    message: Option<Regex>,
+}
+
+impl CompiledRule {
+    fn matches(&self, app: &str, priority: i32, title: &str, message: &str) -> bool {
+        let rule = &self.rule;
+        rule.app.as_deref().map_or(true, |a| a == app)
+            && rule.priority_min.map_or(true, |p| priority >= p)
+            && rule.priority_max.map_or(true, |p| priority <= p)
+            && self.title.as_ref().map_or(true, |r| r.is_match(title))
+            && self.message.as_ref().map_or(true, |r| r.is_match(message))
+    }
+}
+
+pub struct Rules {
+    rules: Vec<CompiledRule>,
+}
+
+impl Rules {
+    pub fn new(rules: &[config::Rule]) -> Result<Rules> {
+        let compile = |regex: &Option<String>, i: usize| -> Result<Option<Regex>> {
+            regex
+                .as_deref()
+                .map(|r| Regex::new(r).with_context(|| format!("Invalid regex in rule #{}", i)))
+                .transpose()
+        };
+
+        let mut compiled = Vec::with_capacity(rules.len());
+        for (i, rule) in rules.iter().enumerate() {
+            if let (Some(min), Some(max)) = (rule.priority_min, rule.priority_max) {
+                if min > max {
+                    bail!(
+                        "priority_min must not be larger than priority_max in rule #{}",
+                        i
+                    );
+                }
+            }
+            compiled.push(CompiledRule {
+                rule: rule.clone(),
+                title: compile(&rule.title, i)?,
+                message: compile(&rule.message, i)?,
+            });
+        }
+        Ok(Rules { rules: compiled })
+    }
+
+    /// Find the first rule matching a message together with its index.
+    pub fn evaluate(
+        &self,
+        app: &str,
+        priority: i32,
+        title: &str,
+        message: &str,
+    ) -> Option<(usize, &config::Rule)> {
+        self.rules
+            .iter()
+            .enumerate()
+            .find(|(_, r)| r.matches(app, priority, title, message))
+            .map(|(i, r)| (i, &r.rule))
+    }
+}
+
+/// Evaluate a sample message against the configured rules and print the outcome.
+pub fn test(
+    config: config::Config,
+    app: &str,
+    priority: i32,
+    title: &str,
+    message: &str,
+) -> Result<()> {
+    let rules = Rules::new(&config.gotify.rule)?;
+    let tier = config::GotifyPriority::from_thresholds(
+        priority,
+        config.gotify.threshold_low,
+        config.gotify.threshold_high,
+    );
+
+    let Some((i, rule)) = rules.evaluate(app, priority, title, message) else {
+        println!("No rule matched, priority: {}", tier.name());
+        return Ok(());
+    };
+
+    println!("Rule #{} matched", i);
+    if rule.drop {
+        println!("   message is dropped");
+        return Ok(());
+    }
+    println!("   priority: {}", rule.priority.unwrap_or(tier).name());
+    if let Some(room_id) = &rule.room_id {
+        println!("   room:     {}", room_id);
+    }
+    if let Some(template) = &rule.template {
+        println!("   template: {}", template);
+    }
+    Ok(())
+}
@@ -1,12 +1,26 @@
 use std::path::{Path, PathBuf};
 
-use matrix_sdk::{config::SyncSettings, matrix_auth::MatrixSession, Client, Error, LoopCtrl};
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use anyhow::{anyhow, bail};
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use matrix_sdk::{
+    config::SyncSettings,
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    ruma::{api::client::error::ErrorKind, OwnedDeviceId, UserId},
+    Client, Error, LoopCtrl, SessionChange, SessionMeta,
+};
+use rand::{distributions::Alphanumeric, thread_rng, Rng, RngCore};
 use serde::{Deserialize, Serialize};
-use tokio::fs;
-use tracing::info;
+use tokio::{fs, io::AsyncWriteExt, sync::broadcast::error::RecvError};
+use tracing::{debug, info, warn};
 
-use crate::config;
+use crate::{
+    config,
+    outbox::{MAX_BACKOFF, MIN_BACKOFF},
+};
 
 /// The data needed to re-build a client.
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,29 +36,39 @@ struct FullSession {
     client_session: ClientSession,
     user_session: MatrixSession,
 
-    #[serde(skip_serializing_if = "Option::is_none")]
+    // only read to migrate it into the store, see `take_last_id`
+    #[serde(default, skip_serializing)]
     last_id: Option<i64>,
 }
 
+// Encrypted session files start with this header, followed by salt, nonce and ciphertext.
+const ENCRYPTED_MAGIC: &[u8] = b"G2MSESS1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
 /// Restore a previous session.
-pub async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<i64>)> {
+pub async fn restore_session(
+    session_file: &Path,
+    session_key: Option<&str>,
+) -> anyhow::Result<(Client, Option<i64>)> {
     info!(
         "Previous session found in '{}'",
         session_file.to_string_lossy()
     );
 
-    // The session was serialized as JSON in a file.
-    let serialized_session = fs::read_to_string(session_file).await?;
+    check_permissions(session_file).await?;
+
     let FullSession {
         client_session,
         user_session,
         last_id,
-    } = serde_json::from_str(&serialized_session)?;
+    } = read_session(session_file, session_key).await?;
 
     // Build the client with the previous settings from the session.
     let client = Client::builder()
         .homeserver_url(client_session.homeserver)
         .sqlite_store(client_session.db_path, Some(&client_session.passphrase))
+        .handle_refresh_tokens()
         .build()
         .await?;
 
@@ -56,22 +80,226 @@ pub async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Opt
     Ok((client, last_id))
 }
 
-pub async fn persist_last_id(session_file: &Path, last_id: Option<i64>) -> anyhow::Result<()> {
-    // nothing to be done for None
-    if last_id.is_none() {
-        return Ok(());
+fn derive_key(session_key: &str, salt: &[u8]) -> anyhow::Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(session_key.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Could not derive key from session key: {e}"))?;
+    Ok(key)
+}
+
+fn encrypt(session_key: &str, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut rng = thread_rng();
+    let mut salt = [0u8; SALT_LEN];
+    rng.fill_bytes(&mut salt);
+    let mut nonce = XNonce::default();
+    rng.fill_bytes(&mut nonce);
+
+    let cipher = XChaCha20Poly1305::new(&derive_key(session_key, &salt)?);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow!("Could not encrypt session"))?;
+    Ok([ENCRYPTED_MAGIC, &salt[..], &nonce[..], &ciphertext[..]].concat())
+}
+
+fn decrypt(session_key: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let data = &data[ENCRYPTED_MAGIC.len()..];
+    if data.len() < SALT_LEN + NONCE_LEN {
+        bail!("Encrypted session file is truncated");
+    }
+    let (salt, data) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+    let cipher = XChaCha20Poly1305::new(&derive_key(session_key, salt)?);
+    cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Could not decrypt session file, is the session key correct?"))
+}
+
+/// Read the session file, decrypting it if it is encrypted.
+async fn read_session(
+    session_file: &Path,
+    session_key: Option<&str>,
+) -> anyhow::Result<FullSession> {
+    let data = fs::read(session_file).await?;
+    let serialized_session = if data.starts_with(ENCRYPTED_MAGIC) {
+        let Some(session_key) = session_key else {
+            bail!("Session file is encrypted, but no session key is configured");
+        };
+        decrypt(session_key, &data)?
+    } else {
+        if session_key.is_some() {
+            warn!(
+                "Session file is not encrypted, encrypt it using `gotify2matrix session encrypt`"
+            );
+        }
+        data
+    };
+
+    // The session was serialized as JSON in a file.
+    Ok(serde_json::from_slice(&serialized_session)?)
+}
+
+/// Write the session file, encrypted if a session key is given.
+async fn write_session(
+    session_file: &Path,
+    full_session: &FullSession,
+    session_key: Option<&str>,
+) -> anyhow::Result<()> {
+    let serialized_session = serde_json::to_vec(full_session)?;
+    let data = match session_key {
+        Some(session_key) => encrypt(session_key, &serialized_session)?,
+        None => serialized_session,
+    };
+    write_file(session_file, &data).await
+}
+
+/// Restore the existing session for subcommands that need one.
+pub async fn restore_existing(config: &config::Config) -> anyhow::Result<Client> {
+    let session_file = config.matrix.session_dir.join("session");
+    if !session_file.exists() {
+        bail!("Session must exist, run gotify2matrix once to log in");
+    }
+    let session_key = config.matrix.session_key()?;
+    let (client, _) = restore_session(&session_file, session_key.as_deref()).await?;
+    tokio::spawn(persist_refreshed_tokens(
+        client.clone(),
+        session_file,
+        session_key,
+    ));
+    Ok(client)
+}
+
+/// Store the recovery key next to the session, encrypted with the session key if configured.
+pub async fn write_recovery_key(config: &config::Config, recovery_key: &str) -> anyhow::Result<()> {
+    let data = match config.matrix.session_key()? {
+        Some(session_key) => encrypt(&session_key, recovery_key.as_bytes())?,
+        None => recovery_key.as_bytes().to_vec(),
+    };
+    write_file(&config.matrix.session_dir.join("recovery_key"), &data).await
+}
+
+pub async fn read_recovery_key(config: &config::Config) -> anyhow::Result<String> {
+    let data = fs::read(config.matrix.session_dir.join("recovery_key")).await?;
+    let data = if data.starts_with(ENCRYPTED_MAGIC) {
+        let Some(session_key) = config.matrix.session_key()? else {
+            bail!("Recovery key is encrypted, but no session key is configured");
+        };
+        decrypt(&session_key, &data)?
+    } else {
+        data
+    };
+    Ok(String::from_utf8(data)?.trim().to_string())
+}
+
+/// Encrypt or decrypt an existing session file with the configured session key.
+pub async fn convert_session(config: &config::Config, encrypted: bool) -> anyhow::Result<()> {
+    let session_file = config.matrix.session_dir.join("session");
+    let Some(session_key) = config.matrix.session_key()? else {
+        bail!("No session key configured");
+    };
+
+    let full_session = read_session(&session_file, Some(&session_key)).await?;
+    if full_session.last_id.is_some() {
+        bail!("Run gotify2matrix once to migrate the session file before converting it");
     }
+    let session_key = encrypted.then_some(session_key.as_str());
+    write_session(&session_file, &full_session, session_key).await?;
+
+    info!(
+        "Session file {} is now {}",
+        session_file.display(),
+        if encrypted { "encrypted" } else { "decrypted" }
+    );
+    Ok(())
+}
+
+/// Remove the session file and the matrix store of the session.
+/// The message store, the recovery key and the dead-letter file are kept.
+pub async fn remove(config: &config::Config) -> anyhow::Result<()> {
+    let session_file = config.matrix.session_dir.join("session");
+    let session_key = config.matrix.session_key()?;
+    let FullSession { client_session, .. } =
+        read_session(&session_file, session_key.as_deref()).await?;
+    if client_session.db_path.exists() {
+        fs::remove_dir_all(&client_session.db_path).await?;
+    }
+    fs::remove_file(&session_file).await?;
+    Ok(())
+}
+
+/// Write a file atomically and only readable by the owner.
+/// The data is written to a temporary file first, which is then renamed.
+async fn write_file(file: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let tmp_file = file.with_extension("tmp");
+    // a leftover temporary file could have wider permissions
+    let _ = fs::remove_file(&tmp_file).await;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut handle = options.open(&tmp_file).await?;
+    handle.write_all(data).await?;
+    handle.sync_all().await?;
+    drop(handle);
+
+    fs::rename(&tmp_file, file).await?;
+    Ok(())
+}
 
-    let serialized_session = fs::read_to_string(session_file).await?;
-    let mut full_session: FullSession = serde_json::from_str(&serialized_session)?;
+/// Restrict session files that are accessible by other users to the owner.
+/// Older versions wrote the session file with the default umask.
+/// Session files readable by anyone that can not be restricted are refused.
+#[cfg(unix)]
+async fn check_permissions(session_file: &Path) -> anyhow::Result<()> {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
 
-    full_session.last_id = last_id;
-    let serialized_session = serde_json::to_string(&full_session)?;
-    fs::write(session_file, serialized_session).await?;
+    let mode = fs::metadata(session_file).await?.permissions().mode();
+    if mode & 0o077 == 0 {
+        return Ok(());
+    }
+    warn!(
+        "Session file {} is accessible by other users (mode {:o}), restricting it to mode 600",
+        session_file.display(),
+        mode & 0o777
+    );
+    if let Err(e) = fs::set_permissions(session_file, Permissions::from_mode(0o600)).await {
+        if mode & 0o007 != 0 {
+            bail!(
+                "Session file {} is accessible by other users (mode {:o}) and could not be restricted: {}, restrict it with `chmod 600`",
+                session_file.display(),
+                mode & 0o777,
+                e
+            );
+        }
+        warn!(
+            "Could not restrict session file {}: {}, consider `chmod 600`",
+            session_file.display(),
+            e
+        );
+    }
+    Ok(())
+}
 
+#[cfg(not(unix))]
+async fn check_permissions(_session_file: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Remove the last id from a session file of the previous format and return it.
+pub async fn take_last_id(
+    session_file: &Path,
+    session_key: Option<&str>,
+) -> anyhow::Result<Option<i64>> {
+    let full_session = read_session(session_file, session_key).await?;
+    if full_session.last_id.is_some() {
+        // last_id is skipped when serializing
+        write_session(session_file, &full_session, session_key).await?;
+    }
+    Ok(full_session.last_id)
+}
+
 /// Login with a new device.
 pub async fn login(
     config: &config::Config,
@@ -83,21 +311,49 @@ pub async fn login(
     let (client, client_session) = build_client(config, data_dir).await?;
     let matrix_auth = client.matrix_auth();
 
-    loop {
-        let username = &config.matrix.username;
-        let password = &config.matrix.password;
-        match matrix_auth
-            .login_username(&username, &password)
+    let matrix = &config.matrix;
+    let username = &matrix.username;
+    if let (Some(access_token), Some(device_id)) = (&matrix.access_token, &matrix.device_id) {
+        client
+            .restore_session(MatrixSession {
+                meta: SessionMeta {
+                    user_id: UserId::parse(username)?,
+                    device_id: device_id.as_str().into(),
+                },
+                tokens: MatrixSessionTokens {
+                    access_token: access_token.clone(),
+                    refresh_token: None,
+                },
+            })
+            .await?;
+        info!("Logged in as {username} using an access token");
+    } else if let Some(login_token) = &matrix.login_token {
+        // login tokens can only be used once, so there is no point in retrying
+        matrix_auth
+            .login_token(login_token)
             .initial_device_display_name("gotify2matrix")
-            .await
-        {
-            Ok(_) => {
-                info!("Logged in as {username}");
-                break;
-            }
-            Err(error) => {
-                info!("Error logging in: {error}");
-                info!("Please try again\n");
+            .request_refresh_token()
+            .await?;
+        info!("Logged in as {username} using a login token");
+    } else {
+        let password = matrix
+            .password()?
+            .ok_or_else(|| anyhow!("No password configured"))?;
+        loop {
+            match matrix_auth
+                .login_username(username, &password)
+                .initial_device_display_name("gotify2matrix")
+                .request_refresh_token()
+                .await
+            {
+                Ok(_) => {
+                    info!("Logged in as {username}");
+                    break;
+                }
+                Err(error) => {
+                    info!("Error logging in: {error}");
+                    info!("Please try again\n");
+                }
             }
         }
     }
@@ -109,18 +365,160 @@ pub async fn login(
     let user_session = matrix_auth
         .session()
         .expect("A logged-in client should have a session");
-    let serialized_session = serde_json::to_string(&FullSession {
+    let full_session = FullSession {
         client_session,
         user_session,
         last_id: None,
-    })?;
-    fs::write(session_file, serialized_session).await?;
+    };
+    let session_key = config.matrix.session_key()?;
+    write_session(session_file, &full_session, session_key.as_deref()).await?;
 
     info!("Session persisted in {}", session_file.to_string_lossy());
 
     Ok(client)
 }
 
+/// Persist the session whenever the access token was refreshed.
+pub async fn persist_refreshed_tokens(
+    client: Client,
+    session_file: PathBuf,
+    session_key: Option<String>,
+) {
+    let mut session_changes = client.subscribe_to_session_changes();
+    loop {
+        match session_changes.recv().await {
+            Ok(SessionChange::TokensRefreshed) | Err(RecvError::Lagged(_)) => {
+                debug!("Access token was refreshed, persisting session");
+                if let Err(e) =
+                    update_user_session(&client, &session_file, session_key.as_deref()).await
+                {
+                    warn!("Could not persist refreshed session: {:?}", e);
+                }
+            }
+            // unknown tokens show up as sync errors, see `is_unknown_token`
+            Ok(_) => {}
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn update_user_session(
+    client: &Client,
+    session_file: &Path,
+    session_key: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut full_session = read_session(session_file, session_key).await?;
+    full_session.user_session = client
+        .matrix_auth()
+        .session()
+        .ok_or_else(|| anyhow!("A logged-in client should have a session"))?;
+    write_session(session_file, &full_session, session_key).await
+}
+
+/// Whether an error means that the access token was invalidated, e.g. by a (soft) logout.
+pub fn is_unknown_token(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<Error>()
+        .and_then(|e| e.client_api_error_kind())
+        .map_or(false, |kind| matches!(kind, ErrorKind::UnknownToken { .. }))
+}
+
+/// Whether the homeserver invalidated the access token with a soft logout,
+/// which keeps the device and its keys.
+pub fn is_soft_logout(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<Error>()
+        .and_then(|e| e.client_api_error_kind())
+        .map_or(false, |kind| {
+            matches!(kind, ErrorKind::UnknownToken { soft_logout: true })
+        })
+}
+
+/// Log in again after the access token was invalidated.
+/// After a soft logout the previous device and its crypto store are reused,
+/// otherwise a new device with a new store is created.
+/// Errors reaching the homeserver are retried. Returns the new client and the previous device id.
+pub async fn relogin(
+    config: &config::Config,
+    session_file: &Path,
+    session_key: Option<&str>,
+    soft_logout: bool,
+) -> anyhow::Result<(Client, OwnedDeviceId)> {
+    let FullSession {
+        client_session,
+        user_session,
+        ..
+    } = read_session(session_file, session_key).await?;
+    let device_id = user_session.meta.device_id;
+    let username = &config.matrix.username;
+    let password = config
+        .matrix
+        .password()?
+        .ok_or_else(|| anyhow!("Logging in again requires a password or password_file"))?;
+
+    // the crypto store of the previous device is useless after a hard logout
+    let old_db_path = (!soft_logout).then(|| client_session.db_path.clone());
+    let (client, client_session) = if soft_logout {
+        info!("Logging in again as {username} with device {device_id}");
+        let client = Client::builder()
+            .homeserver_url(&client_session.homeserver)
+            .sqlite_store(&client_session.db_path, Some(&client_session.passphrase))
+            .handle_refresh_tokens()
+            .build()
+            .await?;
+        (client, client_session)
+    } else {
+        info!("Logging in again as {username} with a new device");
+        build_client(config, &config.matrix.session_dir).await?
+    };
+
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let mut login = client
+            .matrix_auth()
+            .login_username(username, &password)
+            .initial_device_display_name("gotify2matrix")
+            .request_refresh_token();
+        if soft_logout {
+            login = login.device_id(device_id.as_str());
+        }
+        match login.await {
+            Ok(_) => break,
+            // the homeserver could not be reached
+            Err(error) if error.as_client_api_error().is_none() => {
+                warn!("Error logging in: {error}, retrying in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(error) => return Err(error.into()),
+        }
+    }
+    info!("Logged in as {username}");
+
+    let user_session = client
+        .matrix_auth()
+        .session()
+        .expect("A logged-in client should have a session");
+    let full_session = FullSession {
+        client_session,
+        user_session,
+        last_id: None,
+    };
+    write_session(session_file, &full_session, session_key).await?;
+
+    if let Some(old_db_path) = old_db_path {
+        debug!(
+            "Removing the store of the previous device in {}",
+            old_db_path.display()
+        );
+        if let Err(e) = fs::remove_dir_all(&old_db_path).await {
+            warn!("Could not remove {}: {:?}", old_db_path.display(), e);
+        }
+    }
+
+    Ok((client, device_id))
+}
+
 /// Build a new client.
 async fn build_client(
     config: &config::Config,
@@ -156,6 +554,7 @@ async fn build_client(
             // persist the encryption setup.
             // Note that other store backends are available and you can even implement your own.
             .sqlite_store(&db_path, Some(&passphrase))
+            .handle_refresh_tokens()
             .build()
             .await
         {
@@ -194,3 +593,103 @@ pub async fn sync_loop(client: Client, sync_settings: SyncSettings) -> Result<()
         )
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+    use wiremock::{
+        matchers::{body_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    async fn refreshed_tokens_are_persisted() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "versions": ["v1.5"] })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/devices"))
+            .and(header("authorization", "Bearer expired_token"))
+            .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+                "errcode": "M_UNKNOWN_TOKEN",
+                "error": "Access token has expired",
+                "soft_logout": true,
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v3/refresh"))
+            .and(body_json(json!({ "refresh_token": "refresh_token" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "new_token",
+                "refresh_token": "new_refresh_token",
+                "expires_in_ms": 60000,
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/devices"))
+            .and(header("authorization", "Bearer new_token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "devices": [] })))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let session_file = dir.path().join("session");
+        let full_session = FullSession {
+            client_session: ClientSession {
+                homeserver: server.uri(),
+                db_path: dir.path().join("db"),
+                passphrase: "passphrase".to_string(),
+            },
+            user_session: MatrixSession {
+                meta: SessionMeta {
+                    user_id: UserId::parse("@bot:example.org").unwrap(),
+                    device_id: "DEVICE".into(),
+                },
+                tokens: MatrixSessionTokens {
+                    access_token: "expired_token".to_string(),
+                    refresh_token: Some("refresh_token".to_string()),
+                },
+            },
+            last_id: None,
+        };
+        write_session(&session_file, &full_session, None)
+            .await
+            .unwrap();
+
+        let (client, _) = restore_session(&session_file, None).await.unwrap();
+        tokio::spawn(persist_refreshed_tokens(
+            client.clone(),
+            session_file.clone(),
+            None,
+        ));
+        // let the task subscribe to the session changes
+        tokio::task::yield_now().await;
+
+        client.devices().await.unwrap();
+
+        let tokens = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let tokens = read_session(&session_file, None)
+                    .await
+                    .unwrap()
+                    .user_session
+                    .tokens;
+                if tokens.access_token == "new_token" {
+                    break tokens;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Refreshed session was not persisted");
+        assert_eq!(tokens.refresh_token.as_deref(), Some("new_refresh_token"));
+    }
+}
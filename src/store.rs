@@ -0,0 +1,279 @@
+use anyhow::Result;
+use matrix_sdk::ruma::{
+    events::room::message::RoomMessageEventContent, EventId, OwnedRoomId, RoomId,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+// number of pending delivery records that triggers a flush
+const FLUSH_BATCH: usize = 50;
+// interval in which pending delivery records are flushed
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// interval in which old delivery records are pruned
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// age after which delivery records and events are pruned
+const RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How a gotify message was handled.
+#[derive(Debug, Clone, Copy)]
+pub enum Delivery {
+    Sent,
+    Queued,
+    Dropped,
+    DeadLettered,
+}
+
+impl Delivery {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Delivery::Sent => "sent",
+            Delivery::Queued => "queued",
+            Delivery::Dropped => "dropped",
+            Delivery::DeadLettered => "dead_lettered",
+        }
+    }
+}
+
+/// Delivery records that are not yet written to the database.
+#[derive(Default)]
+struct Pending {
+    last_id: Option<i64>,
+    deliveries: Vec<(i64, Delivery)>,
+}
+
+/// A message waiting to be sent to matrix.
+#[derive(Debug, Serialize)]
+pub struct OutboxEntry {
+    pub gotify_id: i64,
+    pub room_id: OwnedRoomId,
+    pub content: RoomMessageEventContent,
+}
+
+/// An outbox row as it is stored, it may not decode into an `OutboxEntry`.
+#[derive(Debug, Serialize)]
+pub struct StoredOutboxEntry {
+    pub gotify_id: i64,
+    pub room_id: String,
+    pub content: String,
+}
+
+impl StoredOutboxEntry {
+    pub fn decode(&self) -> Result<OutboxEntry> {
+        Ok(OutboxEntry {
+            gotify_id: self.gotify_id,
+            room_id: RoomId::parse(&self.room_id)?,
+            content: serde_json::from_str(&self.content)?,
+        })
+    }
+}
+
+/// Local SQLite database next to the matrix-sdk store.
+/// Delivery records are buffered and written in batches, each batch in a single transaction.
+#[derive(Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl Store {
+    pub fn open(path: &Path) -> Result<Store> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = FULL;
+            CREATE TABLE IF NOT EXISTS outbox (
+                gotify_id INTEGER PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                content TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS events (
+                gotify_id INTEGER PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                event_id TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS deliveries (
+                gotify_id INTEGER PRIMARY KEY,
+                status TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS state (
+                key TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            );",
+        )?;
+        Ok(Store {
+            conn: Arc::new(Mutex::new(conn)),
+            pending: Arc::new(Mutex::new(Pending::default())),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().expect("Store mutex was poisoned")
+    }
+
+    fn pending(&self) -> MutexGuard<'_, Pending> {
+        self.pending.lock().expect("Store mutex was poisoned")
+    }
+
+    /// Id of the newest handled gotify message.
+    pub fn last_id(&self) -> Result<Option<i64>> {
+        let pending = self.pending().last_id;
+        let stored = self
+            .conn()
+            .query_row("SELECT value FROM state WHERE key = 'last_id'", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .optional()?;
+        Ok(pending.max(stored))
+    }
+
+    /// Record how a gotify message was handled, this also advances the last id.
+    /// The record is written with the next flush.
+    pub fn mark_delivered(&self, gotify_id: i64, delivery: Delivery) -> Result<()> {
+        let flush = {
+            let mut pending = self.pending();
+            pending.last_id = pending.last_id.max(Some(gotify_id));
+            pending.deliveries.push((gotify_id, delivery));
+            pending.deliveries.len() >= FLUSH_BATCH
+        };
+        if flush {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write all pending delivery records in one transaction.
+    pub fn flush(&self) -> Result<()> {
+        let mut pending = self.pending();
+        if pending.last_id.is_none() && pending.deliveries.is_empty() {
+            return Ok(());
+        }
+        debug!("Flushing {} delivery records", pending.deliveries.len());
+
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        for (gotify_id, delivery) in &pending.deliveries {
+            tx.execute(
+                "INSERT OR REPLACE INTO deliveries (gotify_id, status, updated_at)
+                VALUES (?1, ?2, CAST(strftime('%s', 'now') AS INTEGER))",
+                params![gotify_id, delivery.as_str()],
+            )?;
+        }
+        if let Some(last_id) = pending.last_id {
+            tx.execute(
+                "INSERT INTO state (key, value) VALUES ('last_id', ?1)
+                ON CONFLICT(key) DO UPDATE SET value = max(value, excluded.value)",
+                params![last_id],
+            )?;
+        }
+        tx.commit()?;
+
+        *pending = Pending::default();
+        Ok(())
+    }
+
+    /// Remove delivery records and events of messages handled more than `RETENTION` ago.
+    /// The last id is kept.
+    fn prune(&self) -> Result<()> {
+        let retention = RETENTION.as_secs() as i64;
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM events WHERE gotify_id IN (
+                SELECT gotify_id FROM deliveries
+                WHERE updated_at < CAST(strftime('%s', 'now') AS INTEGER) - ?1
+            )",
+            params![retention],
+        )?;
+        let pruned = tx.execute(
+            "DELETE FROM deliveries WHERE updated_at < CAST(strftime('%s', 'now') AS INTEGER) - ?1",
+            params![retention],
+        )?;
+        tx.commit()?;
+        debug!("Pruned {} delivery records", pruned);
+        Ok(())
+    }
+
+    /// Flush pending delivery records in regular intervals and prune old ones.
+    pub async fn flush_periodically(self) {
+        let mut pruned_at: Option<Instant> = None;
+        loop {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+            if let Err(e) = self.flush() {
+                warn!("Could not flush delivery records: {:?}", e);
+            }
+            if pruned_at.map_or(true, |t| t.elapsed() >= PRUNE_INTERVAL) {
+                if let Err(e) = self.prune() {
+                    warn!("Could not prune delivery records: {:?}", e);
+                }
+                pruned_at = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Import the last id of the previous session file format, unless the store already has one.
+    pub fn migrate_last_id(&self, last_id: i64) -> Result<()> {
+        if self.last_id()?.is_none() {
+            debug!("Migrating last_id {} from session file", last_id);
+            self.conn().execute(
+                "INSERT INTO state (key, value) VALUES ('last_id', ?1)",
+                params![last_id],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn enqueue(
+        &self,
+        gotify_id: i64,
+        room_id: &RoomId,
+        content: &RoomMessageEventContent,
+    ) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO outbox (gotify_id, room_id, content) VALUES (?1, ?2, ?3)",
+            params![gotify_id, room_id.as_str(), serde_json::to_string(content)?],
+        )?;
+        Ok(())
+    }
+
+    /// The oldest message in the outbox.
+    pub fn peek(&self) -> Result<Option<StoredOutboxEntry>> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT gotify_id, room_id, content FROM outbox ORDER BY gotify_id LIMIT 1",
+                [],
+                |row| {
+                    Ok(StoredOutboxEntry {
+                        gotify_id: row.get(0)?,
+                        room_id: row.get(1)?,
+                        content: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn remove(&self, gotify_id: i64) -> Result<()> {
+        self.conn().execute(
+            "DELETE FROM outbox WHERE gotify_id = ?1",
+            params![gotify_id],
+        )?;
+        Ok(())
+    }
+
+    /// Remember the matrix event a gotify message was sent as.
+    pub fn record_event(&self, gotify_id: i64, room_id: &RoomId, event_id: &EventId) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO events (gotify_id, room_id, event_id) VALUES (?1, ?2, ?3)",
+            params![gotify_id, room_id.as_str(), event_id.as_str()],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+use anyhow::{bail, Context, Result};
+use handlebars::Handlebars;
+use serde::Serialize;
+use std::{collections::HashSet, path::Path};
+use tracing::debug;
+
+use crate::{config, helpers};
+
+// names of the templates configured in the gotify section
+const BUILT_IN: [&str; 8] = [
+    "plain",
+    "html",
+    "plain_low",
+    "plain_normal",
+    "plain_high",
+    "html_low",
+    "html_normal",
+    "html_high",
+];
+
+/// The variables available in templates.
+#[derive(Serialize)]
+pub struct Message {
+    pub id: i64,
+    pub app: String,
+    pub app_id: i64,
+    pub app_description: String,
+    pub app_image: String,
+    pub title: String,
+    pub message: String,
+    // sanitized html of a markdown message, the escaped message otherwise
+    pub message_html: String,
+    pub priority: i32,
+    pub priority_name: &'static str,
+    pub date: String,
+    // RFC 3339 date for the date helpers
+    pub timestamp: String,
+    pub extras: serde_json::Value,
+}
+
+impl Message {
+    pub fn render(&self, handlebars: &Handlebars, template: &str) -> Result<String> {
+        let output = handlebars.render(template, self)?;
+        Ok(format!("{}", output))
+    }
+
+    /// A message with every variable set, used to check templates in strict mode.
+    fn sample() -> Message {
+        Message {
+            id: 1,
+            app: "app".to_string(),
+            app_id: 1,
+            app_description: String::new(),
+            app_image: String::new(),
+            title: "title".to_string(),
+            message: "message".to_string(),
+            message_html: "message".to_string(),
+            priority: 5,
+            priority_name: "normal",
+            date: "1970-01-01 00:00:00".to_string(),
+            timestamp: "1970-01-01T00:00:00Z".to_string(),
+            // the extras documented by gotify, others are specific to an application
+            extras: serde_json::json!({
+                "client::display": { "contentType": "text/plain" },
+                "client::notification": {
+                    "click": { "url": "https://gotify.net" },
+                    "bigImageUrl": "https://gotify.net/img/logo.png"
+                },
+                "android::action": {
+                    "onReceive": { "intentUrl": "https://gotify.net" }
+                }
+            }),
+        }
+    }
+}
+
+macro_rules! register {
+    ($templates:expr, $p1:ident.$p2:ident.$p3:ident.$p4:ident) => {
+        debug!("Registering template {}_{}", stringify!($p4), stringify!($p3));
+        if let Some(template) = &$p1.$p2.$p3.$p4 {
+            $templates
+                .register_template_string(
+                    format!("{}_{}", stringify!($p4), stringify!($p3)).as_str(),
+                    template,
+                )
+                .with_context(|| {
+                    format!("Invalid template {}_{}", stringify!($p4), stringify!($p3))
+                })?;
+        }
+    };
+}
+
+/// Compile all configured templates and register the helpers.
+/// In strict mode every template is also rendered with a sample message,
+/// so unknown variables are reported at startup instead of when a message arrives.
+pub fn build<'a>(config: &config::Config) -> Result<Handlebars<'a>> {
+    let mut handlebars = Handlebars::new();
+    handlebars.set_strict_mode(config.gotify.strict_templates);
+    helpers::register(&mut handlebars, &config.gotify)?;
+
+    handlebars
+        .register_template_string("plain", &config.gotify.plain)
+        .context("Invalid template plain")?;
+    handlebars
+        .register_template_string("html", &config.gotify.html)
+        .context("Invalid template html")?;
+    register!(handlebars, config.gotify.low.plain);
+    register!(handlebars, config.gotify.low.html);
+    register!(handlebars, config.gotify.normal.plain);
+    register!(handlebars, config.gotify.normal.html);
+    register!(handlebars, config.gotify.high.plain);
+    register!(handlebars, config.gotify.high.html);
+    for (name, template) in &config.gotify.templates {
+        debug!("Registering named template {}", name);
+        if let Some(plain) = &template.plain {
+            handlebars
+                .register_template_string(&format!("plain:{}", name), plain)
+                .with_context(|| format!("Invalid template plain:{}", name))?;
+        }
+        if let Some(html) = &template.html {
+            handlebars
+                .register_template_string(&format!("html:{}", name), html)
+                .with_context(|| format!("Invalid template html:{}", name))?;
+        }
+    }
+    let mut partials = HashSet::new();
+    if let Some(dir) = &config.gotify.template_dir {
+        for (name, template) in read_dir(dir)? {
+            if handlebars.has_template(&name) {
+                bail!(
+                    "Template {} in {} is already configured in the gotify section",
+                    name,
+                    dir.display()
+                );
+            }
+            handlebars
+                .register_template_string(&name, template)
+                .with_context(|| format!("Invalid template {} in {}", name, dir.display()))?;
+            if selectable_name(&name).is_none() {
+                partials.insert(name);
+            }
+        }
+    }
+
+    if config.gotify.strict_templates {
+        let sample = Message::sample();
+        for name in handlebars.get_templates().keys() {
+            // partials are checked where they are included
+            if partials.contains(name) {
+                continue;
+            }
+            handlebars
+                .render(name, &sample)
+                .with_context(|| format!("Template {} failed to render in strict mode", name))?;
+        }
+    }
+    Ok(handlebars)
+}
+
+/// The same templates without html escaping, used to render the plain text body.
+pub fn without_escaping<'a>(handlebars: &Handlebars<'a>) -> Handlebars<'a> {
+    let mut plain = handlebars.clone();
+    plain.register_escape_fn(handlebars::no_escape);
+    plain
+}
+
+/// Read the `*.hbs` files of the template directory as `(name, template)` pairs.
+/// `name.html.hbs` and `name.plain.hbs` become the named templates `html:name` and `plain:name`,
+/// all other files are partials named after the file, e.g. `header.hbs` is `{{> header}}`.
+/// Files named like the built-in templates, e.g. `plain_low.hbs`, are rejected.
+pub fn read_dir(dir: &Path) -> Result<Vec<(String, String)>> {
+    let mut templates = Vec::new();
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Could not read template dir {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let Some(stem) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".hbs"))
+        else {
+            continue;
+        };
+        let name = if let Some(name) = stem.strip_suffix(".html") {
+            format!("html:{}", name)
+        } else if let Some(name) = stem.strip_suffix(".plain") {
+            format!("plain:{}", name)
+        } else {
+            stem.to_string()
+        };
+        if BUILT_IN.contains(&name.as_str()) {
+            bail!(
+                "Template {} collides with the built-in template {}, rename it",
+                path.display(),
+                name
+            );
+        }
+        debug!("Reading template {} from {}", name, path.display());
+        let template = std::fs::read_to_string(&path)
+            .with_context(|| format!("Could not read template {}", path.display()))?;
+        templates.push((name, template));
+    }
+    Ok(templates)
+}
+
+/// Name of a template that can be selected with `template`, `None` for partials.
+pub fn selectable_name(name: &str) -> Option<&str> {
+    name.strip_prefix("html:")
+        .or_else(|| name.strip_prefix("plain:"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_templates_are_not_escaped() {
+        let mut handlebars = Handlebars::new();
+        handlebars
+            .register_template_string("plain", "{{message}}")
+            .unwrap();
+        handlebars
+            .register_template_string("html", "{{message}}")
+            .unwrap();
+        let message = Message {
+            message: "<a=b>".to_string(),
+            ..Message::sample()
+        };
+
+        let plain = without_escaping(&handlebars);
+        assert_eq!(message.render(&plain, "plain").unwrap(), "<a=b>");
+        assert_eq!(
+            message.render(&handlebars, "html").unwrap(),
+            "&lt;a&#x3D;b&gt;"
+        );
+    }
+}
@@ -1,75 +1,338 @@
 use crate::{config, session};
+use std::collections::HashSet;
 use std::io::Write;
-use anyhow::bail;
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, bail};
 use futures_util::stream::StreamExt;
 use matrix_sdk::{
     config::SyncSettings,
+    event_handler::Ctx,
     encryption::verification::{
-        format_emojis, Emoji, SasState, SasVerification, Verification, VerificationRequest,
-        VerificationRequestState,
+        format_emojis, QrVerification, QrVerificationState, SasState, SasVerification,
+        Verification, VerificationRequest, VerificationRequestState,
     },
     ruma::{
         events::{
             key::verification::request::ToDeviceKeyVerificationRequestEvent,
-            room::message::{MessageType, OriginalSyncRoomMessageEvent},
+            reaction::OriginalSyncReactionEvent,
+            room::message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
         },
-        UserId,
+        DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId,
     },
-    Client,
+    room::RoomMember,
+    Client, RoomMemberships,
 };
-use tracing::info;
+use qrcode::{render::unicode, QrCode};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
 
-pub async fn run(config: config::Config) -> anyhow::Result<()> {
-    let data_dir = &config.matrix.session_dir;
-    let session_file = data_dir.join("session");
-    let (client, _) = if session_file.exists() {
-        session::restore_session(&session_file).await?
+/// Shared state of the verification handlers.
+#[derive(Clone)]
+struct Verifier {
+    // users that may start a verification with the bot
+    allowed_users: Arc<HashSet<OwnedUserId>>,
+    // ask for confirmation in a room instead of on stdin (headless mode),
+    // used if the verification was not requested in a room
+    room_id: Option<OwnedRoomId>,
+    // outcome of the first finished verification, ends the program
+    done: Option<mpsc::UnboundedSender<Result<(), String>>>,
+}
+
+pub async fn run(
+    config: config::Config,
+    headless: bool,
+    user: Option<String>,
+    device: Option<String>,
+) -> anyhow::Result<()> {
+    let client = session::restore_existing(&config).await?;
+    // the room members are only known after a sync
+    client.sync_once(SyncSettings::default()).await?;
+
+    let room_id = if headless {
+        Some(RoomId::parse(&config.matrix.room_id)?)
     } else {
-        bail!("Session must exist before verify");
+        None
     };
+    // wait for more requests after an incoming verification unless running headless
+    let (done, done_rx) = mpsc::unbounded_channel();
+    let verifier = Verifier {
+        allowed_users: allowed_users(&client, &config).await?,
+        room_id,
+        done: (headless || user.is_some()).then_some(done),
+    };
+
+    if let Some(user) = user {
+        let user_id = UserId::parse(user)?;
+        let device_id = device.map(OwnedDeviceId::from);
+        let request = request_verification(&client, &user_id, device_id.as_deref()).await?;
+        tokio::spawn(request_verification_handler(
+            client.clone(),
+            request,
+            verifier.clone(),
+        ));
+    }
 
-    sync(client)
+    sync(client, verifier, done_rx)
         .await
         .map_err(Into::into)
 }
 
+/// Send a verification request to a device or, without a device, to the user.
+async fn request_verification(
+    client: &Client,
+    user_id: &UserId,
+    device_id: Option<&DeviceId>,
+) -> anyhow::Result<VerificationRequest> {
+    let encryption = client.encryption();
+    let request = match device_id {
+        Some(device_id) => {
+            let Some(device) = encryption.get_device(user_id, device_id).await? else {
+                bail!("Unknown device {device_id} of {user_id}");
+            };
+            println!("Requesting verification from {user_id} {device_id}");
+            device.request_verification().await?
+        }
+        None => {
+            let Some(identity) = encryption.get_user_identity(user_id).await? else {
+                bail!("{user_id} has not set up cross-signing, pass a --device to verify");
+            };
+            println!("Requesting verification from {user_id}");
+            identity.request_verification().await?
+        }
+    };
+    Ok(request)
+}
+
+/// Read the allowed users from the config or fall back to the members of the configured rooms.
+async fn allowed_users(
+    client: &Client,
+    config: &config::Config,
+) -> anyhow::Result<Arc<HashSet<OwnedUserId>>> {
+    let mut users = HashSet::new();
+    if !config.verify.allowed_users.is_empty() {
+        for user_id in &config.verify.allowed_users {
+            users.insert(UserId::parse(user_id)?);
+        }
+    } else {
+        for room_id in config.room_ids() {
+            let Some(room) = client.get_room(&RoomId::parse(room_id)?) else {
+                warn!("Not a member of room {room_id}, ignoring its members for verification");
+                continue;
+            };
+            let members = room.members(RoomMemberships::JOIN).await?;
+            users.extend(members.iter().map(RoomMember::user_id).map(ToOwned::to_owned));
+        }
+    }
+    info!(
+        "Accepting verification requests from {}",
+        users.iter().map(|u| u.as_str()).collect::<Vec<_>>().join(", ")
+    );
+    Ok(Arc::new(users))
+}
+
 /// Setup the client to listen to new messages.
 async fn sync(
-    client: Client
+    client: Client,
+    verifier: Verifier,
+    mut done: mpsc::UnboundedReceiver<Result<(), String>>,
 ) -> anyhow::Result<()> {
+    client.add_event_handler_context(verifier);
+
     client.add_event_handler(
-        |ev: ToDeviceKeyVerificationRequestEvent, client: Client| async move {
-            let request = client
+        |ev: ToDeviceKeyVerificationRequestEvent, client: Client, verifier: Ctx<Verifier>| async move {
+            let Some(request) = client
                 .encryption()
                 .get_verification_request(&ev.sender, &ev.content.transaction_id)
                 .await
-                .expect("Request object wasn't created");
+            else {
+                warn!("Verification request from {} is unknown, ignoring it", ev.sender);
+                return;
+            };
 
-            tokio::spawn(request_verification_handler(client, request));
+            if verifier.is_allowed(&request).await {
+                tokio::spawn(request_verification_handler(client, request, verifier.0));
+            }
         },
     );
 
     client.add_event_handler(
-        |ev: OriginalSyncRoomMessageEvent, client: Client| async move {
+        |ev: OriginalSyncRoomMessageEvent, client: Client, verifier: Ctx<Verifier>| async move {
             if let MessageType::VerificationRequest(_) = &ev.content.msgtype {
-                let request = client
+                let Some(request) = client
                     .encryption()
                     .get_verification_request(&ev.sender, &ev.event_id)
                     .await
-                    .expect("Request object wasn't created");
+                else {
+                    warn!(
+                        "Verification request from {} is unknown, ignoring it",
+                        ev.sender
+                    );
+                    return;
+                };
 
-                tokio::spawn(request_verification_handler(client, request));
+                if verifier.is_allowed(&request).await {
+                    tokio::spawn(request_verification_handler(client, request, verifier.0));
+                }
             }
         },
     );
 
     info!("Launching a sync");
-    session::sync_loop(client, SyncSettings::new()).await?;
+    // without `done` only the sync loop ends the program
+    tokio::select! {
+        result = session::sync_loop(client, SyncSettings::new()) => result?,
+        Some(outcome) = done.recv() => {
+            return outcome.map_err(|reason| anyhow!("Verification was cancelled: {reason}"));
+        }
+    }
     Ok(())
 }
 
-async fn wait_for_confirmation(sas: SasVerification, emoji: [Emoji; 7]) {
-    println!("\nDo the emojis match: \n{}", format_emojis(emoji));
+impl Verifier {
+    /// Cancel requests from users that are not allowed to verify the bot.
+    async fn is_allowed(&self, request: &VerificationRequest) -> bool {
+        let user_id = request.other_user_id();
+        if self.allowed_users.contains(user_id) {
+            return true;
+        }
+        warn!("Rejecting verification request from {user_id}: not in allowed_users or the configured rooms");
+        if let Err(e) = request.cancel().await {
+            warn!("Could not cancel verification request from {user_id}: {e}");
+        }
+        false
+    }
+
+    /// Ask a yes/no question in the room in headless mode, otherwise on the terminal.
+    async fn ask(&self, client: &Client, room_id: Option<&RoomId>, question: String) -> bool {
+        match &self.room_id {
+            Some(default_room_id) => {
+                let room_id = room_id.unwrap_or(default_room_id);
+                ask_in_room(client, room_id, &self.allowed_users, question).await
+            }
+            None => ask_on_terminal(question),
+        }
+    }
+
+    /// Report the outcome of a verification if the program should end with it.
+    fn finish(&self, outcome: Result<(), String>) {
+        if let Some(done) = &self.done {
+            let _ = done.send(outcome);
+        }
+    }
+}
+
+/// The running verification flows of one request.
+/// The outcome of the request is only reported once all of its flows ended,
+/// e.g. a QR code is cancelled when the other device starts an emoji verification instead.
+#[derive(Clone)]
+struct Flows {
+    // number of running flows and the outcome so far
+    state: Arc<Mutex<(usize, Option<Result<(), String>>)>>,
+}
+
+impl Flows {
+    /// Flows of a request whose own handler is the first running flow.
+    fn new() -> Flows {
+        Flows {
+            state: Arc::new(Mutex::new((1, None))),
+        }
+    }
+
+    /// Register another flow of the request.
+    fn start(&self) -> Flows {
+        self.state.lock().expect("Flows mutex was poisoned").0 += 1;
+        self.clone()
+    }
+
+    /// End a flow and report the outcome if it was the last one.
+    /// A successful flow takes precedence over cancelled ones.
+    fn end(&self, verifier: &Verifier, outcome: Option<Result<(), String>>) {
+        let mut state = self.state.lock().expect("Flows mutex was poisoned");
+        state.0 -= 1;
+        if let Some(outcome) = outcome {
+            if state.1 != Some(Ok(())) {
+                state.1 = Some(outcome);
+            }
+        }
+        if state.0 == 0 {
+            if let Some(outcome) = state.1.take() {
+                verifier.finish(outcome);
+            }
+        }
+    }
+}
+
+/// Interpret a reply or reaction to the confirmation question.
+fn parse_answer(answer: &str) -> Option<bool> {
+    match answer.trim().trim_end_matches('\u{fe0f}').to_lowercase().as_ref() {
+        "yes" | "true" | "ok" | "👍" | "✅" => Some(true),
+        "no" | "false" | "👎" | "❌" => Some(false),
+        _ => None,
+    }
+}
+
+/// Post the question to a room and wait for an allowed user to reply or react.
+async fn ask_in_room(
+    client: &Client,
+    room_id: &RoomId,
+    allowed_users: &Arc<HashSet<OwnedUserId>>,
+    question: String,
+) -> bool {
+    let Some(room) = client.get_room(room_id) else {
+        warn!("Not a member of room {room_id}, cancelling the verification");
+        return false;
+    };
+
+    let question = RoomMessageEventContent::text_plain(format!(
+        "{question}\nReply with `yes` or `no`, or react with 👍 or 👎."
+    ));
+    let event_id = match room.send(question).await {
+        Ok(response) => response.event_id,
+        Err(e) => {
+            warn!("Could not post to room {room_id}: {e}, cancelling the verification");
+            return false;
+        }
+    };
+
+    let (answer, mut answers) = mpsc::unbounded_channel();
+    let reply_handler = {
+        let answer = answer.clone();
+        let allowed_users = allowed_users.clone();
+        move |ev: OriginalSyncRoomMessageEvent| {
+            if allowed_users.contains(&ev.sender) {
+                if let Some(confirmed) = parse_answer(ev.content.body()) {
+                    let _ = answer.send(confirmed);
+                }
+            }
+            async {}
+        }
+    };
+    let reaction_handler = {
+        let allowed_users = allowed_users.clone();
+        move |ev: OriginalSyncReactionEvent| {
+            let relation = &ev.content.relates_to;
+            if relation.event_id == event_id && allowed_users.contains(&ev.sender) {
+                if let Some(confirmed) = parse_answer(&relation.key) {
+                    let _ = answer.send(confirmed);
+                }
+            }
+            async {}
+        }
+    };
+    let handles = [
+        room.add_event_handler(reply_handler),
+        room.add_event_handler(reaction_handler),
+    ];
+
+    let confirmed = answers.recv().await.unwrap_or(false);
+    for handle in handles {
+        client.remove_event_handler(handle);
+    }
+    confirmed
+}
+
+fn ask_on_terminal(question: String) -> bool {
+    println!("\n{question}");
     print!("Confirm with `yes` or cancel with `no`: ");
     std::io::stdout()
         .flush()
@@ -80,10 +343,15 @@ async fn wait_for_confirmation(sas: SasVerification, emoji: [Emoji; 7]) {
         .read_line(&mut input)
         .expect("error: unable to read user input");
 
-    match input.trim().to_lowercase().as_ref() {
-        "yes" | "true" | "ok" => sas.confirm().await.unwrap(),
-        _ => sas.cancel().await.unwrap(),
-    }
+    parse_answer(&input) == Some(true)
+}
+
+/// Render a QR code with unicode blocks, light on dark to suit most terminals.
+fn render_qr_code(code: &QrCode) -> String {
+    code.render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build()
 }
 
 async fn print_devices(user_id: &UserId, client: &Client) {
@@ -104,38 +372,78 @@ async fn print_devices(user_id: &UserId, client: &Client) {
             continue;
         }
 
-        println!(
-            "   {:<10} {:<30} {:<}",
+        print_device(
             device.device_id(),
-            device.display_name().unwrap_or("-"),
-            if device.is_verified() { "✅" } else { "❌" }
+            device.display_name(),
+            device.is_verified(),
         );
     }
 }
 
-async fn sas_verification_handler(client: Client, sas: SasVerification) {
+pub fn print_device(device_id: &DeviceId, display_name: Option<&str>, verified: bool) {
+    println!(
+        "   {:<10} {:<30} {:<}",
+        device_id,
+        display_name.unwrap_or("-"),
+        if verified { "✅" } else { "❌" }
+    );
+}
+
+async fn sas_verification_handler(
+    client: Client,
+    sas: SasVerification,
+    verifier: Verifier,
+    flows: Flows,
+) {
     println!(
         "Starting verification with {} {}",
         &sas.other_device().user_id(),
         &sas.other_device().device_id()
     );
     print_devices(sas.other_device().user_id(), &client).await;
-    sas.accept().await.unwrap();
+    if !sas.we_started() {
+        if let Err(e) = sas.accept().await {
+            warn!("Could not accept the verification: {e}");
+            flows.end(&verifier, Some(Err(e.to_string())));
+            return;
+        }
+    }
 
     let mut stream = sas.changes();
+    let mut outcome = None;
 
     while let Some(state) = stream.next().await {
         match state {
-            SasState::KeysExchanged {
-                emojis,
-                decimals: _,
-            } => {
-                tokio::spawn(wait_for_confirmation(
-                    sas.clone(),
-                    emojis
-                        .expect("We only support verifications using emojis")
-                        .emojis,
-                ));
+            SasState::KeysExchanged { emojis, decimals } => {
+                let device = sas.other_device();
+                // clients that don't support emojis fall back to decimals
+                let comparison = match emojis {
+                    Some(emojis) => {
+                        format!("Do the emojis match:\n{}", format_emojis(emojis.emojis))
+                    }
+                    None => format!(
+                        "Do the numbers match: {} {} {}",
+                        decimals.0, decimals.1, decimals.2
+                    ),
+                };
+                let question = format!(
+                    "Verification with {} {}\n{comparison}",
+                    device.user_id(),
+                    device.device_id()
+                );
+
+                let (client, sas, verifier) = (client.clone(), sas.clone(), verifier.clone());
+                tokio::spawn(async move {
+                    let confirmed = verifier.ask(&client, sas.room_id(), question).await;
+                    let result = if confirmed {
+                        sas.confirm().await
+                    } else {
+                        sas.cancel().await
+                    };
+                    if let Err(e) = result {
+                        warn!("Could not answer the verification: {e}");
+                    }
+                });
             }
             SasState::Done { .. } => {
                 let device = sas.other_device();
@@ -148,6 +456,7 @@ async fn sas_verification_handler(client: Client, sas: SasVerification) {
                 );
 
                 print_devices(sas.other_device().user_id(), &client).await;
+                outcome = Some(Ok(()));
 
                 break;
             }
@@ -156,38 +465,158 @@ async fn sas_verification_handler(client: Client, sas: SasVerification) {
                     "The verification has been cancelled, reason: {}",
                     cancel_info.reason()
                 );
+                outcome = Some(Err(cancel_info.reason().to_string()));
 
                 break;
             }
             SasState::Started { .. } | SasState::Accepted { .. } | SasState::Confirmed => (),
         }
     }
+    flows.end(&verifier, outcome);
 }
 
-async fn request_verification_handler(client: Client, request: VerificationRequest) {
-    println!(
-        "Accepting verification request from {}",
-        request.other_user_id(),
-    );
-    request
-        .accept()
-        .await
-        .expect("Can't accept verification request");
+async fn qr_verification_handler(
+    client: Client,
+    qr: QrVerification,
+    verifier: Verifier,
+    flows: Flows,
+) {
+    match qr.to_qr_code() {
+        Ok(code) => println!(
+            "Scan this QR code with the other device or start an emoji verification there:\n{}",
+            render_qr_code(&code)
+        ),
+        Err(e) => {
+            warn!("Could not render the QR code: {e}, use an emoji verification instead");
+            flows.end(&verifier, None);
+            return;
+        }
+    }
+
+    let mut stream = qr.changes();
+    let mut outcome = None;
+
+    while let Some(state) = stream.next().await {
+        match state {
+            QrVerificationState::Scanned => {
+                let question = format!(
+                    "{} {} scanned the QR code, does it show a confirmation?",
+                    qr.other_user_id(),
+                    qr.other_device().device_id()
+                );
+                let (client, qr, verifier) = (client.clone(), qr.clone(), verifier.clone());
+                tokio::spawn(async move {
+                    let confirmed = verifier.ask(&client, qr.room_id(), question).await;
+                    let result = if confirmed {
+                        qr.confirm().await
+                    } else {
+                        qr.cancel().await
+                    };
+                    if let Err(e) = result {
+                        warn!("Could not answer the verification: {e}");
+                    }
+                });
+            }
+            QrVerificationState::Done { .. } => {
+                let device = qr.other_device();
 
+                println!(
+                    "Successfully verified device {} {} {:?}",
+                    device.user_id(),
+                    device.device_id(),
+                    device.local_trust_state()
+                );
+
+                print_devices(qr.other_user_id(), &client).await;
+                outcome = Some(Ok(()));
+
+                break;
+            }
+            QrVerificationState::Cancelled(cancel_info) => {
+                println!(
+                    "The QR code verification has been cancelled, reason: {}",
+                    cancel_info.reason()
+                );
+                // an emoji verification of the same request may still be running
+                outcome = Some(Err(cancel_info.reason().to_string()));
+
+                break;
+            }
+            QrVerificationState::Started
+            | QrVerificationState::Confirmed
+            | QrVerificationState::Reciprocated => (),
+        }
+    }
+    flows.end(&verifier, outcome);
+}
+
+async fn request_verification_handler(
+    client: Client,
+    request: VerificationRequest,
+    verifier: Verifier,
+) {
+    if !request.we_started() {
+        println!(
+            "Accepting verification request from {}",
+            request.other_user_id(),
+        );
+        if let Err(e) = request.accept().await {
+            warn!("Could not accept the verification request: {e}");
+            verifier.finish(Err(e.to_string()));
+            return;
+        }
+    }
+
+    // the request handler itself is the first flow
+    let flows = Flows::new();
     let mut stream = request.changes();
+    let mut outcome = None;
 
     while let Some(state) = stream.next().await {
         match state {
             VerificationRequestState::Created { .. }
-            | VerificationRequestState::Requested { .. }
-            | VerificationRequestState::Ready { .. } => (),
+            | VerificationRequestState::Requested { .. } => (),
+            VerificationRequestState::Ready { .. } if request.we_started() => {
+                if let Err(e) = request.start_sas().await {
+                    warn!("Could not start the emoji verification: {e}");
+                }
+            }
+            VerificationRequestState::Ready { .. } => {
+                // only possible if the other device can scan QR codes
+                match request.generate_qr_code().await {
+                    Ok(Some(qr)) => {
+                        tokio::spawn(qr_verification_handler(
+                            client.clone(),
+                            qr,
+                            verifier.clone(),
+                            flows.start(),
+                        ));
+                    }
+                    Ok(None) => info!(
+                        "The other device can't scan QR codes, waiting for an emoji verification"
+                    ),
+                    Err(e) => warn!("Could not generate a QR code: {e}"),
+                }
+            }
             VerificationRequestState::Transitioned { verification } => match verification {
                 Verification::SasV1(s) => {
-                    tokio::spawn(sas_verification_handler(client, s));
+                    tokio::spawn(sas_verification_handler(
+                        client.clone(),
+                        s,
+                        verifier.clone(),
+                        flows.start(),
+                    ));
                     break;
                 }
+                // our own QR code was scanned, it is already handled by the QR handler
+                Verification::QrV1(_) => (),
             },
-            VerificationRequestState::Done | VerificationRequestState::Cancelled(_) => break,
+            VerificationRequestState::Done => break,
+            VerificationRequestState::Cancelled(cancel_info) => {
+                outcome = Some(Err(cancel_info.reason().to_string()));
+                break;
+            }
         }
     }
+    flows.end(&verifier, outcome);
 }